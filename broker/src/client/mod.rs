@@ -4,17 +4,24 @@
 //! it.
 
 mod negotiation;
+mod req_batch;
 mod req_products;
+mod req_query;
+mod send_edits;
 mod send_products;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Error as IoError;
+use std::net::SocketAddr;
 use std::rc::Rc;
+use std::time::Instant;
 
 use either::{Either, Left, Right};
 use futures::{Async, Future, Poll, Stream};
-use futures::future::{empty, err, Empty};
+use futures::future::{empty, err, join_all, Empty};
 use hyper::{Body, Error as HyperError, Method, Request, Response, StatusCode};
-use hyper::header::ContentType;
+use hyper::header::{AcceptEncoding, ContentEncoding, ContentType, IfNoneMatch, RetryAfter};
 use hyper::server::{Http, Service};
 use log::LogLevel;
 use mime;
@@ -24,10 +31,17 @@ use tokio_core::reactor::Handle;
 use url::form_urlencoded::parse as parse_query;
 use void::Void;
 
-use monto3_common::{error_response, json_request};
-use monto3_common::messages::{Language, ProductIdentifier, ProductName};
+use monto3_client::messages::ClientExtension;
+use monto3_common::{decode_request, error_response};
+use monto3_common::encoding::{compress_response, decompress};
+use monto3_common::format::{accept_post, Format};
+use monto3_common::headers::MontoSession;
+use monto3_common::messages::{Language, ProductIdentifier, ProductName, ProtocolVersion,
+                              SoftwareVersion};
 
 use Broker;
+use extensions::Extensions;
+use process::supervise;
 
 type BoxedFuture = Box<Future<Item = Response, Error = Either<HyperError, JsonError>>>;
 
@@ -35,21 +49,43 @@ impl Broker {
     /// Returns a Future that will resolve once the given Future resolves,
     /// serving clients until then.
     ///
+    /// If the configured port is 0, an unused port is chosen by the operating
+    /// system. The address actually being served on can be retrieved with
+    /// `ServeFuture::local_addr`.
+    ///
     /// TODO: This can be made more efficient when
     /// [`conservative_impl_trait`](https://github.com/rust-lang/rust/issues/34511)
     /// is stabilized.
-    pub fn serve_until<F: Future>(self, stop: F) -> ServeFuture<F> {
+    ///
+    /// Services run by the Broker are restarted if they exit while serving,
+    /// and are killed when the returned Future is dropped.
+    pub fn serve_until<F: Future>(mut self, stop: F) -> ServeFuture<F> {
         let listener = TcpListener::bind(&self.config.net.addr, &self.handle)
-            .expect("TODO proper error handling")
-            .incoming();
+            .expect("TODO proper error handling");
+        let addr = listener
+            .local_addr()
+            .expect("TODO proper error handling");
+        info!("Serving on {}", addr);
         let handle = self.handle.clone();
+        let processes = self.processes.drain(..).collect::<Vec<_>>();
         let broker = Rc::new(RefCell::new(self));
+        let supervisors = if processes.is_empty() {
+            None
+        } else {
+            let futures = processes
+                .into_iter()
+                .map(|process| supervise(process, broker.clone(), &handle))
+                .collect::<Vec<_>>();
+            Some(Box::new(join_all(futures)) as Box<Future<Item = _, Error = _>>)
+        };
         ServeFuture {
+            addr,
             broker,
             handle,
             http: Http::new(),
-            listener,
+            listener: listener.incoming(),
             stop,
+            supervisors,
         }
     }
 
@@ -63,8 +99,55 @@ impl Broker {
     }
 }
 
+/// A Client that has negotiated with the Broker.
+#[derive(Debug)]
+pub struct ClientSession {
+    /// The version information of the Client.
+    pub client: SoftwareVersion,
+
+    /// The Client Protocol Extensions enabled.
+    pub extensions: BTreeSet<ClientExtension>,
+
+    /// The handlers for the enabled extensions.
+    pub handlers: Extensions,
+
+    /// The Client Protocol version being used to communicate with the Client.
+    pub protocol: ProtocolVersion,
+
+    /// When the session was last used, to expire it.
+    pub(crate) last_used: Cell<Instant>,
+}
+
 #[derive(Clone)]
-pub(crate) struct Client(pub Rc<RefCell<Broker>>);
+pub(crate) struct Client {
+    pub broker: Rc<RefCell<Broker>>,
+    pub format: Format,
+    pub remote: SocketAddr,
+    pub session: Option<String>,
+}
+
+impl Client {
+    /// Returns whether the Client negotiated the given extension.
+    pub fn negotiated(&self, extension: &ClientExtension) -> bool {
+        let broker = self.broker.borrow();
+        self.session
+            .as_ref()
+            .and_then(|token| broker.session(token))
+            .map(|session| session.extensions.contains(extension))
+            .unwrap_or(false)
+    }
+
+    /// Returns the handlers for the extensions enabled for the Client's
+    /// session.
+    pub fn handlers(&self) -> Extensions {
+        let broker = self.broker.borrow();
+        self.session
+            .as_ref()
+            .and_then(|token| broker.session(token))
+            .map(|session| session.handlers.clone())
+            .unwrap_or_default()
+    }
+}
 
 impl Service for Client {
     type Request = Request;
@@ -73,14 +156,87 @@ impl Service for Client {
     type Future = Box<Future<Item = Response<Body>, Error = HyperError>>;
 
     fn call(&self, req: Request) -> Self::Future {
+        let (recorder, handle) = {
+            let broker = self.broker.borrow();
+            (broker.recorder.clone(), broker.handle.clone())
+        };
+        match recorder {
+            Some(recorder) => {
+                let client = self.clone();
+                recorder.client(req, &handle, move |req| client.route(req))
+            }
+            None => self.route(req),
+        }
+    }
+}
+
+impl Client {
+    /// Handles a request from the Client.
+    fn route(&self, req: Request) -> Box<Future<Item = Response<Body>, Error = HyperError>> {
         let (method, uri, _, headers, body) = req.deconstruct();
         let path_str = uri.path().to_string();
         let mut query_pairs = parse_query(uri.query().unwrap_or("").as_bytes());
         let path = uri.path().split("/").collect::<Vec<_>>();
+        let accept = headers.get::<AcceptEncoding>().cloned();
+        let encoding = headers.get::<ContentEncoding>().cloned();
+        let client = Client {
+            broker: self.broker.clone(),
+            format: Format::from_accept(headers.get()),
+            remote: self.remote,
+            session: headers.get().map(|&MontoSession(ref token)| token.clone()),
+        };
+
+        // Negotiation is never limited, so that Clients can always connect.
+        let mut permit = if path == ["", "monto", "version"] {
+            None
+        } else {
+            let resolves = match (&method, &path) {
+                (&Method::Get, path) => path.len() == 4 && path[0].is_empty() && path[1] == "monto",
+                (&Method::Post, path) => {
+                    path == &["", "monto", "products"] || path == &["", "monto", "query"]
+                }
+                _ => false,
+            };
+            // Only sessions the Broker started count, so that made-up tokens
+            // can't be used to get around the limits.
+            let (limiter, session) = {
+                let broker = self.broker.borrow();
+                let session = client
+                    .session
+                    .clone()
+                    .filter(|token| broker.session(token).is_some());
+                (broker.limiter.clone(), session)
+            };
+            match limiter.admit(self.remote, session, resolves) {
+                Ok(permit) => Some(permit),
+                Err(retry) => {
+                    warn!("429 {} {} from {}", method, path_str, self.remote);
+                    return Box::new(
+                        error_response(StatusCode::TooManyRequests)
+                            .map(move |r| r.with_header(RetryAfter::Delay(retry))),
+                    );
+                }
+            }
+        };
         let f: BoxedFuture = match (method.clone(), &path) {
             (Method::Post, path) if path == &["", "monto", "version"] => {
-                let client = self.clone();
-                Box::new(json_request(body).and_then(move |cn| client.negotiation(cn)))
+                Box::new(
+                    decode_request(body, &headers)
+                        .and_then(move |cn| client.negotiation(cn)),
+                )
+            }
+            (Method::Post, path) if path == &["", "monto", "products"] => {
+                Box::new(
+                    decode_request(body, &headers)
+                        .and_then(move |brs| client.req_batch(brs)),
+                )
+            }
+            (Method::Post, path) if path == &["", "monto", "query"] => {
+                let permit = permit.take();
+                Box::new(
+                    decode_request(body, &headers)
+                        .and_then(move |q| client.req_query(q, permit)),
+                )
             }
             (Method::Put, path)
                 if path.len() == 4 && path[0] == "" && path[1] == "monto"
@@ -95,7 +251,6 @@ impl Service for Client {
                     .find(|&(ref k, _)| k == "language")
                     .map(|(_, v)| v.into_owned())
                     .map(Language::from);
-                let client = self.clone();
                 let ContentType(content_type) = headers
                     .get()
                     .map(Clone::clone)
@@ -103,17 +258,45 @@ impl Service for Client {
                 match (content_type.type_(), content_type.subtype()) {
                     (mime::TEXT, mime::PLAIN) => if pt == ProductName::Source {
                         Box::new(body.concat2().map_err(Left).and_then(move |b| {
-                            let b = String::from_utf8_lossy(b.as_ref()).into_owned();
-                            client.send_products(pt, pp, language, Value::String(b))
+                            match decompress(b.to_vec(), encoding.as_ref()) {
+                                Ok(b) => {
+                                    let b = String::from_utf8_lossy(&b).into_owned();
+                                    client.send_products(pt, pp, language, Value::String(b))
+                                }
+                                Err(e) => {
+                                    error!("{}", e);
+                                    Box::new(error_response(StatusCode::BadRequest).map_err(Left))
+                                }
+                            }
                         }))
                     } else {
                         panic!("TODO Error Handling");
                     },
-                    (mime::APPLICATION, mime::JSON) => Box::new(
-                        json_request(body)
-                            .and_then(move |p| client.send_products(pt, pp, language, p)),
-                    ),
-                    _ => unimplemented!(),
+                    _ => match Format::from_mime(&content_type) {
+                        Some(_) => Box::new(
+                            decode_request(body, &headers)
+                                .and_then(move |p| client.send_products(pt, pp, language, p)),
+                        ),
+                        None => Box::new(
+                            error_response(StatusCode::UnsupportedMediaType).map_err(Left),
+                        ),
+                    },
+                }
+            }
+            (Method::Patch, path) if path == &["", "monto", "broker", "source"] => {
+                let mut params = query_pairs.collect::<BTreeMap<_, _>>();
+                match params.remove("path") {
+                    Some(pp) => {
+                        let pp = pp.into_owned();
+                        let language = params
+                            .remove("language")
+                            .map(|l| Language::from(l.into_owned()));
+                        Box::new(
+                            decode_request(body, &headers)
+                                .and_then(move |patch| client.send_edits(pp, language, patch)),
+                        )
+                    }
+                    None => Box::new(error_response(StatusCode::BadRequest).map_err(Left)),
                 }
             }
             (Method::Get, path) if path.len() == 4 && path[0] == "" && path[1] == "monto" => {
@@ -128,16 +311,23 @@ impl Service for Client {
                     .map(|(_, v)| v.into_owned())
                     .map(Language::from)
                     .expect("TODO Error handling");
-                Box::new(self.clone().req_products(
+                Box::new(client.req_products(
                     service_id,
                     ProductIdentifier {
                         language,
                         name: product_type,
                         path: product_path,
                     },
+                    headers.get::<IfNoneMatch>().cloned(),
                 ))
             }
-            _ => Box::new(error_response(StatusCode::NotFound).map_err(Left)),
+            _ => match client
+                .handlers()
+                .handle(&client.broker, &method, &uri, &headers, body)
+            {
+                Some(f) => Box::new(f.map_err(Left)),
+                None => Box::new(error_response(StatusCode::NotFound).map_err(Left)),
+            },
         };
         Box::new(
             f.or_else(|e| {
@@ -150,7 +340,9 @@ impl Service for Client {
                     // If it's serde's though, transform it into a 500.
                     Right(_) => error_response(StatusCode::InternalServerError),
                 }
-            }).map(move |r| {
+            }).map(|r| r.with_header(accept_post()))
+                .and_then(move |r| compress_response(r, accept.as_ref()))
+                .map(move |r| {
                     let status = r.status();
                     let level = if status.is_server_error() || status.is_strange_status() {
                         LogLevel::Error
@@ -161,6 +353,10 @@ impl Service for Client {
                     };
                     log!(level, "{} {} {}", u16::from(r.status()), method, path_str);
                     r
+                })
+                .then(move |r| {
+                    drop(permit);
+                    r
                 }),
         )
     }
@@ -168,11 +364,20 @@ impl Service for Client {
 
 /// A Future for the Broker serving to Clients.
 pub struct ServeFuture<F: Future> {
+    addr: SocketAddr,
     broker: Rc<RefCell<Broker>>,
     handle: Handle,
     http: Http,
     listener: Incoming,
     stop: F,
+    supervisors: Option<Box<Future<Item = Vec<()>, Error = IoError>>>,
+}
+
+impl<F: Future> ServeFuture<F> {
+    /// Returns the address being served on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
 }
 
 impl<F: Future> Future for ServeFuture<F> {
@@ -180,12 +385,32 @@ impl<F: Future> Future for ServeFuture<F> {
     type Error = F::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let supervised = match self.supervisors {
+            Some(ref mut supervisors) => match supervisors.poll() {
+                Ok(Async::NotReady) => true,
+                Ok(Async::Ready(_)) => false,
+                Err(e) => {
+                    error!("Stopped supervising services: {}", e);
+                    false
+                }
+            },
+            None => true,
+        };
+        if !supervised {
+            self.supervisors = None;
+        }
+
         match self.stop.poll() {
             Ok(Async::NotReady) => loop {
                 match self.listener.poll() {
                     Ok(Async::Ready(Some((stream, remote)))) => {
                         info!("Got client connection from {}", remote);
-                        let service = Client(self.broker.clone());
+                        let service = Client {
+                            broker: self.broker.clone(),
+                            format: Format::Json,
+                            remote,
+                            session: None,
+                        };
                         self.http
                             .bind_connection(&self.handle, stream, remote, service);
                     }
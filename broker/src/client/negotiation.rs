@@ -1,23 +1,45 @@
+use std::cell::Cell;
+use std::time::Instant;
+
+use futures::Future;
 use hyper::StatusCode;
 
 use monto3_common::json_response;
+use monto3_common::headers::MontoSession;
 use monto3_client::messages::ClientNegotiation;
+use monto3_common::messages::ProtocolVersion;
 
-use client::{BoxedFuture, Client};
+use client::{BoxedFuture, Client, ClientSession};
 
 impl Client {
     /// Performs negotiation.
+    ///
+    /// If the Client is compatible, the highest protocol version both support
+    /// is chosen, and a session is started for it. The session's token is
+    /// returned in the `Monto-Session` header.
     pub fn negotiation(self, cn: ClientNegotiation) -> BoxedFuture {
         debug!("Got ClientNegotiation {:?}", cn);
-        let broker = self.0.borrow();
+        let mut broker = self.broker.borrow_mut();
 
-        let cbn = broker.client_negotiation();
-        let status = if cbn.monto.compatible(&cn.monto) {
-            StatusCode::Ok
-        } else {
-            StatusCode::BadRequest
+        let mut cbn = broker.client_negotiation();
+        let protocol = match ProtocolVersion::negotiate(&cbn.supported(), &cn.supported()) {
+            Some(protocol) => protocol,
+            None => return json_response(cbn, StatusCode::BadRequest),
         };
+        cbn.monto = protocol;
 
-        json_response(cbn, status)
+        let extensions = cbn.extensions
+            .intersection(&cn.extensions)
+            .cloned()
+            .collect();
+        let handlers = broker.extensions.enabled(&extensions, &Default::default());
+        let token = broker.new_session(ClientSession {
+            client: cn.client,
+            extensions,
+            handlers,
+            protocol,
+            last_used: Cell::new(Instant::now()),
+        });
+        Box::new(json_response(cbn, StatusCode::Ok).map(|res| res.with_header(MontoSession(token))))
     }
 }
@@ -0,0 +1,43 @@
+use either::Either;
+use futures::{Future, Stream};
+use futures::future::ok;
+use futures::stream::iter_ok;
+use hyper::{Error as HyperError, StatusCode};
+use serde_json::Error as JsonError;
+
+use monto3_common::format_response;
+use monto3_client::messages::{BatchRequest, BatchResponse};
+
+use client::{BoxedFuture, Client};
+
+impl Client {
+    /// Handles a batch of requests for products sent to the broker.
+    ///
+    /// The requests are resolved one after another, so a dependency cached
+    /// while resolving one request is reused by the requests after it.
+    pub fn req_batch(self, requests: Vec<BatchRequest>) -> BoxedFuture {
+        let handlers = self.handlers();
+        let format = self.format;
+        Box::new(
+            iter_ok::<_, Either<HyperError, JsonError>>(requests)
+                .and_then(move |BatchRequest { service, product }| {
+                    let handlers = handlers.clone();
+                    self.clone()
+                        .resolve(service, product, vec![])
+                        .then(move |r| {
+                            ok(match r {
+                                Ok(mut product) => {
+                                    for handler in handlers.client() {
+                                        handler.product(&mut product);
+                                    }
+                                    BatchResponse::Product(product)
+                                }
+                                Err(err) => BatchResponse::Error(err),
+                            })
+                        })
+                })
+                .collect()
+                .and_then(move |responses| format_response(responses, StatusCode::Ok, format)),
+        )
+    }
+}
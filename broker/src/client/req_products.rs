@@ -1,7 +1,8 @@
 use futures::Future;
 use hyper::StatusCode;
+use hyper::header::IfNoneMatch;
 
-use monto3_common::json_response;
+use monto3_common::{format_response_tagged, json_response};
 use monto3_common::messages::{Identifier, ProductIdentifier};
 use monto3_client::messages::BrokerGetError;
 
@@ -9,10 +10,25 @@ use client::{BoxedFuture, Client};
 
 impl Client {
     /// Handles a request for products sent to the broker.
-    pub fn req_products(self, service_id: Identifier, product: ProductIdentifier) -> BoxedFuture {
+    ///
+    /// If the client already has the product, as indicated by the
+    /// `If-None-Match` header, a `304 Not Modified` is returned instead.
+    pub fn req_products(
+        self,
+        service_id: Identifier,
+        product: ProductIdentifier,
+        if_none_match: Option<IfNoneMatch>,
+    ) -> BoxedFuture {
+        let handlers = self.handlers();
+        let format = self.format;
         Box::new(self.resolve(service_id, product, vec![]).then(
-            |r| match r {
-                Ok(product) => json_response(product, StatusCode::Ok),
+            move |r| match r {
+                Ok(mut product) => {
+                    for handler in handlers.client() {
+                        handler.product(&mut product);
+                    }
+                    format_response_tagged(product, StatusCode::Ok, format, if_none_match.as_ref())
+                }
                 Err(err) => {
                     let status = match err {
                         BrokerGetError::NoSuchService => StatusCode::BadRequest,
@@ -20,6 +36,7 @@ impl Client {
                         BrokerGetError::ServiceError { .. } => StatusCode::InternalServerError,
                         BrokerGetError::ServiceConnectError { .. } => StatusCode::BadGateway,
                         BrokerGetError::Unresolvable(_) => StatusCode::InternalServerError,
+                        BrokerGetError::SourceChanged => StatusCode::Conflict,
                     };
                     json_response(err, status)
                 }
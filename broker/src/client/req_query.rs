@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use either::Left;
+use futures::{Future, Sink, Stream};
+use futures::future::ok;
+use futures::stream::iter_ok;
+use globset::Glob;
+use hyper::{Body, Response, StatusCode};
+use hyper::header::ContentType;
+use serde_json::to_vec;
+
+use monto3_client::messages::{BrokerGetError, ClientExtension, QueryResponse, WorkspaceQuery};
+use monto3_common::error_response;
+use monto3_common::messages::{Identifier, Language, ProductIdentifier};
+use monto3_common::paths::file_path;
+
+use client::{BoxedFuture, Client};
+use limit::Permit;
+use resolve::walk_directory;
+
+impl Client {
+    /// Handles a request for a product for every file under a directory, if
+    /// the Client negotiated the `ClientExtension::Query` extension.
+    ///
+    /// The directory is walked on the Broker's thread pool, since it may be
+    /// large. The products are then resolved one after another, like a
+    /// batch, and each is streamed back as a line of JSON as soon as it is
+    /// resolved, regardless of the format the Client asked for. The request's
+    /// Permit is held until the last product is streamed, so the whole query
+    /// counts against the Client's requests in flight.
+    pub fn req_query(self, query: WorkspaceQuery, permit: Option<Permit>) -> BoxedFuture {
+        if !self.negotiated(&ClientExtension::Query) {
+            return Box::new(error_response(StatusCode::NotFound).map_err(Left));
+        }
+        let (hidden, pool) = {
+            let broker = self.broker.borrow();
+            let pool = broker.cache.borrow().pool();
+            (broker.config.broker.list_hidden_files, pool)
+        };
+        let service = query.service.clone();
+        let walk = pool.spawn_fn(move || match query_products(&query, hidden) {
+            Ok(products) => {
+                info!("Querying {} for {} files", query.product, products.len());
+                Ok(products)
+            }
+            Err(e) => {
+                warn!("Bad workspace query {:?}: {}", query, e);
+                Err(())
+            }
+        });
+        Box::new(walk.then(move |r| -> BoxedFuture {
+            match r {
+                Ok(products) => self.stream_query(service, products, permit),
+                Err(()) => Box::new(error_response(StatusCode::BadRequest).map_err(Left)),
+            }
+        }))
+    }
+
+    /// Resolves the products for a workspace query, streaming each back as
+    /// it is resolved.
+    fn stream_query(
+        self,
+        service: Identifier,
+        products: Vec<ProductIdentifier>,
+        permit: Option<Permit>,
+    ) -> BoxedFuture {
+        let (sender, body) = Body::pair();
+        let handlers = self.handlers();
+        let handle = self.broker.borrow().handle.clone();
+        let task = iter_ok::<_, ()>(products).fold(sender, move |sender, pi| {
+            let (handlers, service) = (handlers.clone(), service.clone());
+            self.clone()
+                .resolve(service.clone(), pi.clone(), vec![])
+                .then(move |r| {
+                    let response = match r {
+                        Ok(mut product) => {
+                            for handler in handlers.client() {
+                                handler.product(&mut product);
+                            }
+                            QueryResponse::Product(product)
+                        }
+                        Err(error) => QueryResponse::Error {
+                            product: pi.clone(),
+                            error,
+                        },
+                    };
+                    let mut line = to_vec(&response)
+                        .or_else(|e| {
+                            error!("Couldn't serialize {:?}: {}", pi, e);
+                            to_vec(&QueryResponse::Error {
+                                product: pi,
+                                error: BrokerGetError::ServiceError {
+                                    service,
+                                    error: e.to_string(),
+                                },
+                            })
+                        })
+                        .map_err(|e| error!("{}", e))?;
+                    line.push(b'\n');
+                    Ok(line)
+                })
+                // If the Client hangs up, there's no point resolving the rest.
+                .and_then(|line| sender.send(Ok(line.into())).map_err(|_| ()))
+        });
+        handle.spawn(task.then(move |_| {
+            drop(permit);
+            Ok(())
+        }));
+
+        let res = Response::new()
+            .with_header(ContentType("application/x-ndjson".parse().unwrap()))
+            .with_body(body);
+        Box::new(ok(res))
+    }
+}
+
+/// Finds the products to resolve for a workspace query.
+fn query_products(query: &WorkspaceQuery, hidden: bool) -> Result<Vec<ProductIdentifier>, String> {
+    let glob = match query.glob {
+        Some(ref glob) => Some(
+            Glob::new(glob)
+                .map_err(|e| e.to_string())?
+                .compile_matcher(),
+        ),
+        None => None,
+    };
+    let root = file_path(&query.root)
+        .ok_or_else(|| format!("{} is not a file path", query.root))?
+        .canonicalize()
+        .map_err(|e| e.to_string())?;
+    let files = walk_directory(&query.root, hidden).map_err(|e| e.to_string())?;
+
+    Ok(files
+        .into_iter()
+        .filter_map(|path| {
+            let guessed = guess_language(&path);
+            let matches = match glob {
+                Some(ref glob) => path.strip_prefix(&root)
+                    .map(|relative| glob.is_match(relative))
+                    .unwrap_or(false),
+                None => query.language.is_none() || guessed == query.language,
+            };
+            let language = query.language.clone().or(guessed);
+            match language {
+                Some(language) if matches => Some(ProductIdentifier {
+                    name: query.product.clone(),
+                    language,
+                    path: path.display().to_string(),
+                }),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/// Guesses the language of a file from its extension.
+fn guess_language(path: &Path) -> Option<Language> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("c") | Some("h") => Some(Language::C),
+        Some("json") => Some(Language::Json),
+        Some("txt") => Some(Language::Text),
+        _ => None,
+    }
+}
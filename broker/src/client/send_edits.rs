@@ -0,0 +1,127 @@
+use futures::future::ok;
+use hyper::{Response, StatusCode};
+use hyper::header::{ETag, EntityTag};
+use serde_json::Value;
+
+use monto3_client::messages::{BrokerPutError, SourceEdit, SourcePatch};
+use monto3_common::json_response;
+use monto3_common::messages::{Language, Product, ProductIdentifier, ProductName};
+
+use client::{BoxedFuture, Client};
+
+impl Client {
+    /// Handles edits to a source product being sent to the broker.
+    pub fn send_edits(
+        self,
+        path: String,
+        language: Option<Language>,
+        patch: SourcePatch,
+    ) -> BoxedFuture {
+        let language = match language {
+            Some(language) => language,
+            None => return json_response(BrokerPutError::NoLanguage, StatusCode::BadRequest),
+        };
+
+        let broker = self.broker.borrow_mut();
+        let mut cache = broker.cache.borrow_mut();
+
+        let current = cache.source_version(&path);
+        let src = cache.get(&broker.version().id, ProductIdentifier {
+            name: ProductName::Source,
+            language: language.clone(),
+            path: path.clone(),
+        });
+        let src = match (src, current) {
+            (Some(Product { value: Value::String(src), .. }), Some(current))
+                if current == patch.version =>
+            {
+                src
+            }
+            (_, current) => {
+                warn!(
+                    "Got edits to version {} of {}, but have {:?}",
+                    patch.version,
+                    path,
+                    current
+                );
+                return json_response(
+                    BrokerPutError::VersionMismatch { current },
+                    StatusCode::Conflict,
+                );
+            }
+        };
+        let src = match apply_edits(src, &patch.edits) {
+            Ok(src) => src,
+            Err(edit) => {
+                return json_response(BrokerPutError::BadEdit(edit), StatusCode::BadRequest)
+            }
+        };
+
+        cache.add(broker.version().id, Product {
+            name: ProductName::Source,
+            language: language.clone(),
+            path: path.clone(),
+            value: Value::String(src),
+        });
+
+        let mut res = Response::new().with_status(StatusCode::NoContent);
+        if let Some(version) = cache.source_version(&path) {
+            res.headers_mut()
+                .set(ETag(EntityTag::strong(version.to_string())));
+        }
+        drop(cache);
+        drop(broker);
+        self.precompute(&path, &language);
+        Box::new(ok(res))
+    }
+}
+
+/// Applies edits to a source, in order. If an edit does not fit the source, it
+/// is returned as the error.
+fn apply_edits(mut src: String, edits: &[SourceEdit]) -> Result<String, SourceEdit> {
+    for edit in edits {
+        if edit.start_byte > edit.end_byte || !src.is_char_boundary(edit.start_byte)
+            || !src.is_char_boundary(edit.end_byte)
+        {
+            return Err(edit.clone());
+        }
+        let tail = src.split_off(edit.end_byte);
+        src.truncate(edit.start_byte);
+        src.push_str(&edit.text);
+        src.push_str(&tail);
+    }
+    Ok(src)
+}
+
+#[test]
+fn apply_edits_in_order() {
+    let edits = vec![
+        SourceEdit {
+            start_byte: 4,
+            end_byte: 5,
+            text: "long".to_string(),
+        },
+        SourceEdit {
+            start_byte: 0,
+            end_byte: 0,
+            text: "unsigned ".to_string(),
+        },
+    ];
+    let src = apply_edits("int x = 1;".to_string(), &edits).unwrap();
+    assert_eq!(src, "unsigned int long = 1;");
+}
+
+#[test]
+fn apply_edits_out_of_bounds() {
+    let edits = vec![
+        SourceEdit {
+            start_byte: 2,
+            end_byte: 12,
+            text: String::new(),
+        },
+    ];
+    assert_eq!(
+        apply_edits("int x = 1;".to_string(), &edits),
+        Err(edits[0].clone())
+    );
+}
@@ -1,5 +1,6 @@
 use futures::future::ok;
 use hyper::{Response, StatusCode};
+use hyper::header::{ETag, EntityTag};
 use serde_json::Value;
 
 use monto3_client::messages::BrokerPutError;
@@ -22,18 +23,32 @@ impl Client {
             None => return json_response(BrokerPutError::NoLanguage, StatusCode::BadRequest),
         };
 
-        let broker = self.0.borrow_mut();
-        let mut cache = broker.cache.borrow_mut();
-
+        let source = if name == ProductName::Source {
+            Some((path.clone(), language.clone()))
+        } else {
+            None
+        };
         let gp = Product {
             name,
             path,
             language,
             value,
         };
-        cache.add(gp);
+        let (id, cache) = {
+            let broker = self.broker.borrow();
+            (broker.version().id, broker.cache.clone())
+        };
+        cache.borrow_mut().add(id, gp);
 
-        Box::new(ok(Response::new().with_status(StatusCode::NoContent)))
+        let mut res = Response::new().with_status(StatusCode::NoContent);
+        if let Some((path, language)) = source {
+            if let Some(version) = cache.borrow().source_version(&path) {
+                res.headers_mut()
+                    .set(ETag(EntityTag::strong(version.to_string())));
+            }
+            self.precompute(&path, &language);
+        }
+        Box::new(ok(res))
     }
 
     /// Detects the language of a Product.
@@ -1,13 +1,17 @@
 //! The configuration for the Broker.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use globset::{Error as GlobError, Glob, GlobSet, GlobSetBuilder};
+use num_cpus;
 use url::Url;
 
 use monto3_client::messages::ClientExtension;
-use monto3_common::messages::{Identifier, SoftwareVersion};
+use monto3_common::format::Format;
+use monto3_common::messages::{Identifier, Language, ProductName, ProtocolVersion,
+                              SoftwareVersion};
 use monto3_service::messages::ServiceExtension;
 
 /// The Broker's configuration.
@@ -25,6 +29,15 @@ use monto3_service::messages::ServiceExtension;
 /// [[service]]
 /// addr = "localhost:12345"
 /// base = "/silver/monto"
+///
+/// [[precompute]]
+/// language = "c"
+/// products = ["errors", "highlighting"]
+///
+/// [[translate]]
+/// from = "edu.umn.cs.melt.ablec.errors"
+/// to = "errors"
+/// language = "c"
 /// ```
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
@@ -36,12 +49,24 @@ pub struct Config {
     /// Configuration for extensions to the Monto protocols.
     pub extensions: ExtensionConfig,
 
+    /// Configuration for limits on how much load each Client may put on the
+    /// Broker.
+    pub limits: LimitConfig,
+
     /// Configuration for the Broker's interface with Clients.
     pub net: NetConfig,
 
+    /// Configuration for products to compute in the background when a source
+    /// is sent to the Broker.
+    pub precompute: Vec<PrecomputeConfig>,
+
     /// Configuration for the services to connect to.
     pub service: Vec<ServiceConfig>,
 
+    /// Configuration for products the Broker produces by translating the
+    /// products of Services.
+    pub translate: Vec<TranslateConfig>,
+
     /// Configuration on how the Broker should report its version and implementation.
     pub version: VersionConfig,
 }
@@ -85,12 +110,21 @@ impl Config {
         let matches = clap_app!((name) =>
             (version: version)
             (@arg CONFIG: --config +takes_value "The path to the config file.")
+            (@arg RECORD: --record +takes_value "A file to record the session to.")
+            (@arg REPLAY: --replay +takes_value "A recorded session to replay.")
         ).get_matches();
-        if let Some(config_path) = matches.value_of_os("CONFIG") {
+        let mut config = if let Some(config_path) = matches.value_of_os("CONFIG") {
             Config::load_one(&config_path).unwrap_or_else(|| panic!("Failed to load config."))
         } else {
             Config::load()
+        };
+        if let Some(path) = matches.value_of_os("RECORD") {
+            config.broker.record = Some(path.into());
         }
+        if let Some(path) = matches.value_of_os("REPLAY") {
+            config.broker.replay = Some(path.into());
+        }
+        config
     }
 
     fn load_one<P: AsRef<Path>>(dir: P) -> Option<Config> {
@@ -134,42 +168,196 @@ impl Config {
 ///
 /// ## Example
 /// ```toml
+/// format = "cbor"
+/// list_hidden_files = false
+/// poll = ["/home/**", "/mnt/nfs/**"]
+/// poll_interval = 2000
+/// protocols = [{ major = 3, minor = 0, patch = 0 }]
+/// record = "session.jsonl"
 /// service_failure_is_fatal = true
+/// service_timeout = 30000
+/// threads = 4
 /// ```
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(default)]
 pub struct BrokerConfig {
+    /// The format to send products to Services in, and to ask for products
+    /// from them in. JSON is used instead with Services that don't support
+    /// this format.
+    ///
+    /// Defaults to JSON.
+    pub format: Format,
+
+    /// Whether to include hidden files in `directory` products. Files ignored
+    /// by `.gitignore` or `.ignore` files are never included.
+    ///
+    /// Defaults to false.
+    pub list_hidden_files: bool,
+
+    /// Globs for paths to watch for changes by polling them, rather than with
+    /// the operating system's file change notifications. This is needed for
+    /// filesystems that don't support notifications, such as NFS. Other paths
+    /// are still watched with notifications. `"**"` polls all paths.
+    ///
+    /// Defaults to none.
+    pub poll: Vec<String>,
+
+    /// How often to poll the paths matching `poll`, in milliseconds.
+    ///
+    /// Defaults to 2000.
+    pub poll_interval: u64,
+
+    /// The versions of the Client and Service Protocols to accept. The highest
+    /// version shared with each Client and Service is used to communicate with
+    /// it.
+    ///
+    /// Defaults to all the versions this implementation supports.
+    pub protocols: BTreeSet<ProtocolVersion>,
+
+    /// A file to record all requests from Clients, all requests to Services,
+    /// and their responses to. See the `record` module for details.
+    ///
+    /// Defaults to not recording.
+    pub record: Option<PathBuf>,
+
+    /// A file to replay a recorded session from. Instead of connecting to the
+    /// configured Services, the Broker stands in for the Services recorded in
+    /// the session.
+    ///
+    /// Defaults to not replaying.
+    pub replay: Option<PathBuf>,
+
     /// Whether to treat failure to connect to a Service during startup as fatal.
     ///
     /// Defaults to true.
     pub service_failure_is_fatal: bool,
+
+    /// How long to wait for a Service to respond to a request for a product,
+    /// in milliseconds, before giving up on it.
+    ///
+    /// Defaults to 30000.
+    pub service_timeout: u64,
+
+    /// The number of threads used to read watched files when checking whether
+    /// they changed. The Broker's state is still accessed from a single
+    /// thread.
+    ///
+    /// Defaults to the number of CPUs.
+    pub threads: usize,
 }
 
 impl Default for BrokerConfig {
     fn default() -> BrokerConfig {
         BrokerConfig {
+            format: Format::Json,
+            list_hidden_files: false,
+            poll: Vec::new(),
+            poll_interval: 2000,
+            protocols: ProtocolVersion::supported(),
+            record: None,
+            replay: None,
             service_failure_is_fatal: true,
+            service_timeout: 30_000,
+            threads: num_cpus::get(),
         }
     }
 }
 
+impl BrokerConfig {
+    /// Compiles the globs in `poll`.
+    pub fn poll_globs(&self) -> Result<GlobSet, GlobError> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in &self.poll {
+            builder.add(Glob::new(glob)?);
+        }
+        builder.build()
+    }
+
+    /// Returns the highest protocol version to accept.
+    ///
+    /// Panics if no versions are configured; `Broker::new` returns an error
+    /// for such a config instead.
+    pub fn preferred_protocol(&self) -> ProtocolVersion {
+        *self.protocols
+            .iter()
+            .next_back()
+            .expect("No protocol versions configured")
+    }
+}
+
 /// The configuration for extensions.
 ///
 /// ## Example
 ///
 /// ```toml
-/// client = ["com.example.foo"]
+/// client = ["com.example/foo"]
 /// service = []
 /// ```
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct ExtensionConfig {
-    /// Client Protocol Extensions that are available.
+    /// Client Protocol Extensions that are available, in addition to those
+    /// with a registered `ClientExtensionHandler`.
     pub client: BTreeSet<ClientExtension>,
 
-    /// Service Protocol Extensions that are available.
+    /// Service Protocol Extensions that are available, in addition to those
+    /// with a registered `ServiceExtensionHandler`.
     pub service: BTreeSet<ServiceExtension>,
 }
 
+/// The configuration for per-Client limits on requests. Requests over a limit
+/// are responded to with a 429 (Too Many Requests) status.
+///
+/// Negotiation requests are never limited.
+///
+/// ## Example
+///
+/// ```toml
+/// by = "session"
+/// burst = 50
+/// max_in_flight = 4
+/// rate = 20
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LimitConfig {
+    /// How Clients are told apart.
+    ///
+    /// Defaults to `"address"`.
+    pub by: LimitBy,
+
+    /// The number of requests a Client may make at once after being idle. If
+    /// not set, this is the same as `rate`. Must not be zero.
+    pub burst: Option<u32>,
+
+    /// The maximum number of requests for products a Client may have being
+    /// resolved at once. If not set, there is no limit.
+    pub max_in_flight: Option<usize>,
+
+    /// The number of requests per second a Client may make on average. If not
+    /// set, there is no limit. Must not be zero.
+    pub rate: Option<u32>,
+}
+
+/// How Clients are told apart for the purposes of limiting requests.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitBy {
+    /// By the IP address the request came from.
+    Address,
+
+    /// By the session token sent with the request, falling back to the IP
+    /// address the request came from if none was sent, or if the token isn't
+    /// one the Broker gave out.
+    Session,
+}
+
+impl Default for LimitBy {
+    fn default() -> LimitBy {
+        LimitBy::Address
+    }
+}
+
 /// The configuration for how the Broker serves to Clients.
 ///
 /// ## Example
@@ -180,7 +368,9 @@ pub struct ExtensionConfig {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(default)]
 pub struct NetConfig {
-    /// The address to serve on. Defaults to `0.0.0.0:28888`.
+    /// The address to serve on. If the port is 0, an unused port is chosen.
+    ///
+    /// Defaults to `0.0.0.0:28888`.
     pub addr: SocketAddr,
 }
 
@@ -195,13 +385,114 @@ impl Default for NetConfig {
     }
 }
 
+/// The configuration for products to compute in the background whenever a
+/// source is sent to the Broker, so that they are already cached when a
+/// Client asks for them.
+///
+/// Each product is computed by whichever Service provides it for the
+/// source's language, like a dependency would be.
+///
+/// ## Example
+///
+/// ```toml
+/// glob = "/home/*/src/**/*.c"
+/// language = "c"
+/// products = ["errors", "highlighting"]
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PrecomputeConfig {
+    /// A glob the path of the source must match. If not set, sources at any
+    /// path match.
+    #[serde(default)]
+    pub glob: Option<String>,
+
+    /// The language the source must be in. If not set, sources in any
+    /// language match.
+    #[serde(default)]
+    pub language: Option<Language>,
+
+    /// The products to compute, in the language of the source.
+    pub products: Vec<ProductName>,
+}
+
+/// The configuration for a product the Broker produces itself, by
+/// translating another product of the same source. This lets a product
+/// specific to one Service be exposed as a standard one.
+///
+/// If no `fields` or `positions` are given, the product is passed through
+/// unchanged, so the translated product is just an alias. Otherwise, the
+/// product must be an object or an array of objects, and each object is
+/// replaced by one with only the given fields.
+///
+/// ## Example
+///
+/// ```toml
+/// from = "edu.umn.cs.melt.ablec.errors"
+/// to = "errors"
+/// language = "c"
+///
+/// [fields]
+/// message = "message"
+/// severity = "severity"
+///
+/// [positions]
+/// start_byte = { line = "start_line", column = "start_col" }
+/// end_byte = { line = "end_line", column = "end_col" }
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TranslateConfig {
+    /// The product to translate.
+    pub from: ProductName,
+
+    /// The product to produce.
+    pub to: ProductName,
+
+    /// The language of both products.
+    pub language: Language,
+
+    /// Fields to copy into each object, as a map from the name of the field in
+    /// the translated product to the name of the field in the original one.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+
+    /// Byte offsets to compute from lines and columns in each object, as a map
+    /// from the name of the field in the translated product to the fields
+    /// holding the line and column in the original one.
+    #[serde(default)]
+    pub positions: BTreeMap<String, PositionConfig>,
+
+    /// Whether lines and columns start at 1 rather than 0.
+    ///
+    /// Defaults to false.
+    #[serde(default)]
+    pub one_based: bool,
+}
+
+/// The fields holding a line and column, which are translated to a byte
+/// offset. Columns are counted in characters.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PositionConfig {
+    /// The field holding the line.
+    pub line: String,
+
+    /// The field holding the column.
+    pub column: String,
+}
+
 /// The configuration for a Broker to connect to a Service.
 ///
+/// If a `command` is given, the Broker runs the Service itself: it starts the
+/// command before connecting to the Service, restarts it if it exits, and
+/// stops it when the Broker stops serving.
+///
 /// ## Example
 ///
 /// ```toml
 /// addr = "localhost:1234"
 /// base = "/monto"
+/// command = ["monto-parenlang", "--config", "parenlang.toml"]
+/// dir = "/home/user/monto"
+/// env = { RUST_LOG = "info" }
 /// ```
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ServiceConfig {
@@ -212,6 +503,23 @@ pub struct ServiceConfig {
     #[serde(default = "ServiceConfig::default_base")]
     pub base: String,
 
+    /// The command line to run the Service with. If empty, the Service is
+    /// assumed to already be running.
+    ///
+    /// Defaults to empty.
+    #[serde(default)]
+    pub command: Vec<String>,
+
+    /// The working directory to run the Service in. If not set, the Broker's
+    /// working directory is used.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+
+    /// Environment variables to run the Service with, in addition to the
+    /// Broker's.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
     /// The URI Scheme to use. Defaults to "http".
     #[serde(default = "ServiceConfig::default_scheme")]
     pub scheme: String,
@@ -0,0 +1,149 @@
+//! Implementations of Client and Service Protocol Extensions.
+//!
+//! An extension is negotiated by name, as in the specification. If an
+//! extension handler is registered for a name, its hooks are called for the
+//! Clients and Services that negotiated it, and only for them.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::rc::Rc;
+
+use futures::Future;
+use hyper::{Body, Error as HyperError, Headers, Method, Request, Response, Uri};
+
+use monto3_client::messages::ClientExtension;
+use monto3_common::messages::Product;
+use monto3_service::messages::ServiceExtension;
+
+use Broker;
+
+/// A Future for the Response to a request handled by an extension.
+pub type ExtensionFuture = Box<Future<Item = Response, Error = HyperError>>;
+
+/// An implementation of a Client Protocol Extension.
+pub trait ClientExtensionHandler {
+    /// Returns the extension being implemented.
+    fn extension(&self) -> ClientExtension;
+
+    /// Handles a request the Broker does not handle itself. If the extension
+    /// does not handle it either, the body should be returned.
+    fn handle(
+        &self,
+        _broker: &Rc<RefCell<Broker>>,
+        _method: &Method,
+        _uri: &Uri,
+        _headers: &Headers,
+        body: Body,
+    ) -> Result<ExtensionFuture, Body> {
+        Err(body)
+    }
+
+    /// Modifies a Product before it is sent to the Client.
+    fn product(&self, _product: &mut Product) {}
+}
+
+/// An implementation of a Service Protocol Extension.
+pub trait ServiceExtensionHandler {
+    /// Returns the extension being implemented.
+    fn extension(&self) -> ServiceExtension;
+
+    /// Modifies a request before it is sent to the Service.
+    fn request(&self, _req: &mut Request) {}
+
+    /// Modifies a Product after it is received from the Service.
+    fn product(&self, _product: &mut Product) {}
+}
+
+/// A set of extension handlers.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    client: Vec<Rc<ClientExtensionHandler>>,
+    service: Vec<Rc<ServiceExtensionHandler>>,
+}
+
+impl Extensions {
+    /// Adds a handler for a Client Protocol Extension, replacing any handler
+    /// previously added for the same extension.
+    pub fn add_client<H: ClientExtensionHandler + 'static>(&mut self, handler: H) {
+        let extension = handler.extension();
+        self.client.retain(|h| h.extension() != extension);
+        self.client.push(Rc::new(handler));
+    }
+
+    /// Adds a handler for a Service Protocol Extension, replacing any handler
+    /// previously added for the same extension.
+    pub fn add_service<H: ServiceExtensionHandler + 'static>(&mut self, handler: H) {
+        let extension = handler.extension();
+        self.service.retain(|h| h.extension() != extension);
+        self.service.push(Rc::new(handler));
+    }
+
+    /// Returns the handlers for Client Protocol Extensions.
+    pub fn client(&self) -> &[Rc<ClientExtensionHandler>] {
+        &self.client
+    }
+
+    /// Returns the handlers for Service Protocol Extensions.
+    pub fn service(&self) -> &[Rc<ServiceExtensionHandler>] {
+        &self.service
+    }
+
+    /// Returns the Client Protocol Extensions there are handlers for.
+    pub fn client_extensions(&self) -> BTreeSet<ClientExtension> {
+        self.client.iter().map(|h| h.extension()).collect()
+    }
+
+    /// Returns the Service Protocol Extensions there are handlers for.
+    pub fn service_extensions(&self) -> BTreeSet<ServiceExtension> {
+        self.service.iter().map(|h| h.extension()).collect()
+    }
+
+    /// Returns only the handlers for the given extensions.
+    pub fn enabled(
+        &self,
+        client: &BTreeSet<ClientExtension>,
+        service: &BTreeSet<ServiceExtension>,
+    ) -> Extensions {
+        Extensions {
+            client: self.client
+                .iter()
+                .filter(|h| client.contains(&h.extension()))
+                .cloned()
+                .collect(),
+            service: self.service
+                .iter()
+                .filter(|h| service.contains(&h.extension()))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Gives a request to each Client Protocol Extension handler in turn,
+    /// returning the Response of the first one to handle it.
+    pub(crate) fn handle(
+        &self,
+        broker: &Rc<RefCell<Broker>>,
+        method: &Method,
+        uri: &Uri,
+        headers: &Headers,
+        mut body: Body,
+    ) -> Option<ExtensionFuture> {
+        for handler in &self.client {
+            match handler.handle(broker, method, uri, headers, body) {
+                Ok(future) => return Some(future),
+                Err(b) => body = b,
+            }
+        }
+        None
+    }
+}
+
+impl Debug for Extensions {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Extensions")
+            .field("client", &self.client_extensions())
+            .field("service", &self.service_extensions())
+            .finish()
+    }
+}
@@ -7,7 +7,10 @@ extern crate either;
 #[macro_use]
 extern crate error_chain;
 extern crate futures;
+extern crate futures_cpupool;
+extern crate globset;
 extern crate hyper;
+extern crate ignore;
 extern crate itertools;
 #[macro_use]
 extern crate log;
@@ -16,6 +19,8 @@ extern crate monto3_client;
 extern crate monto3_common;
 extern crate monto3_service;
 extern crate notify;
+extern crate num_cpus;
+extern crate rand;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -27,31 +32,55 @@ extern crate void;
 
 pub mod client;
 pub mod config;
+pub mod extensions;
+pub mod limit;
+pub mod process;
+pub mod record;
 pub mod resolve;
 pub mod service;
 
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Error as IoError;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use futures::Future;
-use futures::future::{err, join_all};
+use futures::future::{err, join_all, ok};
+use futures_cpupool::Builder as CpuPoolBuilder;
+use globset::Error as GlobError;
 use notify::Error as NotifyError;
 use tokio_core::reactor::Handle;
 
-use monto3_client::messages::ClientBrokerNegotiation;
-use monto3_common::messages::{Identifier, ProtocolVersion, SoftwareVersion};
-use monto3_service::messages::ServiceBrokerNegotiation;
+use monto3_client::messages::{ClientBrokerNegotiation, ClientExtension};
+use monto3_common::messages::{Identifier, Language, ProductDescriptor, ProductName,
+                              SoftwareVersion};
+use monto3_service::messages::{ServiceBrokerNegotiation, ServiceNegotiation};
 
+use client::ClientSession;
 use config::Config;
-use resolve::Cache;
+use extensions::Extensions;
+use limit::Limiter;
+use process::Process;
+use record::{read_session, Record, Recorder};
+use resolve::{Cache, Precompute};
 use service::{Service, ServiceConnectError, ServiceConnectErrorKind};
 
+/// How long a Client's session lasts after it was last used, in seconds.
+const SESSION_TTL: u64 = 24 * 60 * 60;
+
 /// The Broker.
 pub struct Broker {
     cache: Rc<RefCell<Cache>>,
     config: Config,
+    extensions: Extensions,
     handle: Handle,
+    limiter: Limiter,
+    precompute: Vec<Precompute>,
+    processes: Vec<Process>,
+    recorder: Option<Recorder>,
     services: Vec<Service>,
+    sessions: BTreeMap<String, ClientSession>,
 
     // TODO
 }
@@ -67,29 +96,147 @@ impl Broker {
         config: Config,
         handle: Handle,
     ) -> Box<Future<Item = Broker, Error = NewBrokerError>> {
-        let cache = match Cache::new(&handle) {
+        Broker::with_extensions(config, Extensions::default(), handle)
+    }
+
+    /// Creates a new instance of the Broker with the given extension
+    /// handlers, returning a Future for the constructed Broker.
+    pub fn with_extensions(
+        config: Config,
+        extensions: Extensions,
+        handle: Handle,
+    ) -> Box<Future<Item = Broker, Error = NewBrokerError>> {
+        if config.broker.protocols.is_empty() {
+            return Box::new(err(NewBrokerErrorKind::NoProtocols.into()));
+        }
+        if config.limits.rate == Some(0) {
+            return Box::new(err(NewBrokerErrorKind::ZeroLimit("rate").into()));
+        }
+        if config.limits.burst == Some(0) {
+            return Box::new(err(NewBrokerErrorKind::ZeroLimit("burst").into()));
+        }
+        let pool = CpuPoolBuilder::new()
+            .pool_size(config.broker.threads.max(1))
+            .name_prefix("monto3-broker-")
+            .create();
+        let cache = match Cache::new(&config.broker, pool, &handle) {
             Ok(cache) => cache,
+            Err(e) => return Box::new(err(e)),
+        };
+        let recorder = match config.broker.record {
+            Some(ref path) => match Recorder::create(path) {
+                Ok(recorder) => Some(recorder),
+                Err(e) => return Box::new(err(e.into())),
+            },
+            None => None,
+        };
+        let mut processes = Vec::new();
+        let services: Box<Future<Item = Vec<Service>, Error = NewBrokerError>> =
+            match config.broker.replay {
+                Some(ref path) => {
+                    let records = match read_session(path) {
+                        Ok(records) => records,
+                        Err(e) => return Box::new(err(e.into())),
+                    };
+                    let services = records
+                        .iter()
+                        .filter_map(|record| match *record {
+                            Record::Service(ref sn) => Some(Service::replay(sn.clone(), &records)),
+                            _ => None,
+                        })
+                        .collect();
+                    Box::new(ok(services))
+                }
+                None => {
+                    let mut futures = Vec::new();
+                    for s in config.service.clone() {
+                        if s.command.is_empty() {
+                            let connect = Service::connect(config.clone(), s, &extensions, &handle)
+                                .map_err(NewBrokerError::from);
+                            futures.push(Box::new(connect) as Box<Future<Item = _, Error = _>>);
+                            continue;
+                        }
+                        let process = match Process::spawn(s.clone()) {
+                            Ok(process) => process,
+                            Err(e) => return Box::new(err(e.into())),
+                        };
+                        let (config, extensions, handle) =
+                            (config.clone(), extensions.clone(), handle.clone());
+                        let connect = process.listening(&handle).from_err().and_then(move |()| {
+                            Service::connect(config, s, &extensions, &handle).from_err()
+                        });
+                        processes.push(process);
+                        futures.push(Box::new(connect));
+                    }
+                    Box::new(join_all(futures))
+                }
+            };
+        let limiter = match Limiter::new(config.limits.clone(), &handle) {
+            Ok(limiter) => limiter,
             Err(e) => return Box::new(err(e.into())),
         };
-        let futures = config
-            .service
-            .clone()
-            .into_iter()
-            .map(|s| {
-                Service::connect(config.clone(), s, &handle).map_err(NewBrokerError::from)
-            })
-            .collect::<Vec<_>>();
-        Box::new(join_all(futures).map(|services| {
+        let precompute = match config.precompute.iter().map(Precompute::new).collect() {
+            Ok(precompute) => precompute,
+            Err(e) => return Box::new(err(NewBrokerError::from(e))),
+        };
+        Box::new(services.map(|mut services| {
             info!("Connected to all services: {:?}", services);
+            if let Some(ref recorder) = recorder {
+                for service in &mut services {
+                    service.record_to(recorder.clone());
+                }
+            }
             Broker {
                 cache,
                 config,
+                extensions,
                 handle,
+                limiter,
+                precompute,
+                processes,
+                recorder,
                 services,
+                sessions: BTreeMap::new(),
             }
         }))
     }
 
+    /// Adds a Service that runs in the Broker's process. Its products are
+    /// resolved like those of any other Service, but without going through
+    /// HTTP.
+    pub fn add_service(&mut self, service: monto3_service::Service) {
+        info!(
+            "Adding in-process service {}",
+            service.negotiation().service.id
+        );
+        let mut service = Service::in_process(service);
+        if let Some(ref recorder) = self.recorder {
+            service.record_to(recorder.clone());
+        }
+        self.services.push(service);
+    }
+
+    /// Returns the cache of products.
+    pub fn cache(&self) -> Rc<RefCell<Cache>> {
+        self.cache.clone()
+    }
+
+    /// Replaces the connection to the Service at the same address as the
+    /// given one, for when a Service run by the Broker is restarted.
+    pub fn replace_service(&mut self, mut service: Service) {
+        if let Some(ref recorder) = self.recorder {
+            service.record_to(recorder.clone());
+        }
+        let addr = service.config().map(|c| c.addr.clone());
+        match self.services
+            .iter()
+            .position(|s| s.config().map(|c| c.addr.clone()) == addr)
+        {
+            Some(i) => self.services[i] = service,
+            None => self.services.push(service),
+        }
+    }
+
     /// Returns the service with the given id, if one exists.
     pub fn find_service(&self, id: &Identifier) -> Option<&Service> {
         for service in self.services.iter() {
@@ -101,35 +248,86 @@ impl Broker {
     }
 
     /// Creates a ClientBrokerNegotiation.
+    ///
+    /// The Broker lists itself as a service, for the products it produces
+    /// itself.
     pub fn client_negotiation(&self) -> ClientBrokerNegotiation {
+        let mut services = self.services
+            .iter()
+            .map(|s| s.negotiation.clone())
+            .collect::<BTreeSet<_>>();
+        services.insert(self.native_negotiation());
         ClientBrokerNegotiation {
-            monto: ProtocolVersion {
-                major: 3,
-                minor: 0,
-                patch: 0,
-            },
+            monto: self.config.broker.preferred_protocol(),
+            versions: self.config.broker.protocols.clone(),
             broker: self.version(),
-            extensions: self.config.extensions.client.clone(),
-            services: self.services
-                .iter()
-                .map(|s| s.negotiation.clone())
-                .collect(),
+            extensions: self.client_extensions(),
+            services,
+        }
+    }
+
+    /// Returns the Client Protocol Extensions the Broker supports.
+    pub fn client_extensions(&self) -> BTreeSet<ClientExtension> {
+        let mut extensions = self.config.extensions.client.clone();
+        extensions.extend(self.extensions.client_extensions());
+        extensions.insert(ClientExtension::Query);
+        extensions
+    }
+
+    /// Creates a ServiceNegotiation describing the products the Broker
+    /// produces itself, including those it produces by translation, and the
+    /// errors it merges from every Service.
+    pub fn native_negotiation(&self) -> ServiceNegotiation {
+        let mut products = BTreeSet::new();
+        products.insert(ProductDescriptor {
+            name: ProductName::Directory,
+            language: Language::None,
+        });
+        products.extend(self.merged_products());
+        products.extend(self.translated_products());
+        ServiceNegotiation {
+            monto: self.config.broker.preferred_protocol(),
+            versions: self.config.broker.protocols.clone(),
+            service: self.version(),
+            extensions: BTreeSet::new(),
+            products,
         }
     }
 
     /// Creates a ServiceBrokerNegotiation.
     pub fn service_negotiation(&self) -> ServiceBrokerNegotiation {
         ServiceBrokerNegotiation {
-            monto: ProtocolVersion {
-                major: 3,
-                minor: 0,
-                patch: 0,
-            },
-            extensions: self.config.extensions.service.clone(),
+            monto: self.config.broker.preferred_protocol(),
+            versions: self.config.broker.protocols.clone(),
+            extensions: service::supported_extensions(&self.config, &self.extensions),
             broker: self.version(),
         }
     }
 
+    /// Starts a session for a Client that has negotiated with the Broker,
+    /// returning the session's token. Sessions that haven't been used for
+    /// `SESSION_TTL` seconds are ended.
+    pub fn new_session(&mut self, session: ClientSession) -> String {
+        let ttl = Duration::from_secs(SESSION_TTL);
+        self.sessions.retain(|_, s| s.last_used.get().elapsed() < ttl);
+
+        let token = format!("{:016x}", rand::random::<u64>());
+        self.sessions.insert(token.clone(), session);
+        token
+    }
+
+    /// Returns the session with the given token, if one exists and hasn't
+    /// expired.
+    pub fn session(&self, token: &str) -> Option<&ClientSession> {
+        let session = self.sessions.get(token)?;
+        let now = Instant::now();
+        if now.duration_since(session.last_used.get()) >= Duration::from_secs(SESSION_TTL) {
+            return None;
+        }
+        session.last_used.set(now);
+        Some(session)
+    }
+
     /// Returns the version information for the Broker.
     pub fn version(&self) -> SoftwareVersion {
         self.config.version.clone().into()
@@ -141,6 +339,10 @@ error_chain! {
         NewBrokerError, NewBrokerErrorKind, NewBrokerResultExt;
     }
     foreign_links {
+        Glob(GlobError)
+            #[doc = "An invalid glob in the `poll` configuration."];
+        Io(IoError)
+            #[doc = "An error opening a session file, starting a timer, or starting a service."];
         Notify(NotifyError)
             #[doc = "An error setting up the notifier."];
     }
@@ -148,4 +350,17 @@ error_chain! {
         ServiceConnect(ServiceConnectError, ServiceConnectErrorKind)
             #[doc = "An error connecting to a service."];
     }
+    errors {
+        /// The config did not list any protocol versions to use.
+        NoProtocols {
+            description("The config did not list any protocol versions")
+        }
+
+        /// A limit in the `limits` config was zero, which would refuse every
+        /// request.
+        ZeroLimit(name: &'static str) {
+            description("A limit in the config was zero")
+            display("The `{}` limit can't be zero", name)
+        }
+    }
 }
@@ -0,0 +1,226 @@
+//! Per-Client limits on the rate of requests and on the number of requests
+//! being resolved at once.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Error as IoError;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use tokio_core::reactor::{Handle, Interval};
+
+use config::{LimitBy, LimitConfig};
+
+/// How often to forget Clients that are idle, in seconds.
+const SWEEP_INTERVAL: u64 = 60;
+
+/// How long Clients are told to wait when they have too many requests being
+/// resolved, since it isn't known when one will finish.
+const IN_FLIGHT_RETRY: u64 = 1;
+
+/// Tracks the requests made by each Client, and decides whether to accept
+/// new ones.
+#[derive(Clone, Debug)]
+pub struct Limiter(Rc<RefCell<LimiterState>>);
+
+#[derive(Debug)]
+struct LimiterState {
+    clients: BTreeMap<ClientKey, ClientState>,
+    config: LimitConfig,
+}
+
+/// What a Client is identified by.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum ClientKey {
+    Address(IpAddr),
+    Session(String),
+}
+
+#[derive(Debug)]
+struct ClientState {
+    in_flight: usize,
+    last: Instant,
+    tokens: f64,
+}
+
+impl Limiter {
+    /// Creates a Limiter with the given limits. Clients that are idle are
+    /// periodically forgotten, until the Limiter is dropped.
+    pub fn new(config: LimitConfig, handle: &Handle) -> Result<Limiter, IoError> {
+        let state = Rc::new(RefCell::new(LimiterState {
+            clients: BTreeMap::new(),
+            config,
+        }));
+        let weak = Rc::downgrade(&state);
+        let sweep = Interval::new(Duration::from_secs(SWEEP_INTERVAL), handle)?
+            .map_err(|e| error!("Couldn't forget idle clients: {}", e))
+            .take_while(move |()| {
+                Ok(match weak.upgrade() {
+                    Some(state) => {
+                        state.borrow_mut().sweep();
+                        true
+                    }
+                    None => false,
+                })
+            })
+            .for_each(|()| Ok(()));
+        handle.spawn(sweep);
+        Ok(Limiter(state))
+    }
+
+    /// Decides whether to accept a request from a Client. If `resolves` is
+    /// true, the request is for products, and counts against the Client's
+    /// requests in flight until the returned Permit is dropped.
+    ///
+    /// The session token should only be given if it belongs to a session the
+    /// Broker started, so Clients can't get new limits by making up tokens.
+    ///
+    /// If the request is refused, returns how long the Client should wait
+    /// before retrying.
+    pub fn admit(
+        &self,
+        remote: SocketAddr,
+        session: Option<String>,
+        resolves: bool,
+    ) -> Result<Permit, Duration> {
+        let mut state = self.0.borrow_mut();
+        let key = match (state.config.by, session) {
+            (LimitBy::Session, Some(token)) => ClientKey::Session(token),
+            _ => ClientKey::Address(remote.ip()),
+        };
+        let (rate, burst) = state.rates();
+        let max_in_flight = state.config.max_in_flight;
+        let now = Instant::now();
+
+        let client = state.clients.entry(key.clone()).or_insert(ClientState {
+            in_flight: 0,
+            last: now,
+            tokens: burst.unwrap_or(0.0),
+        });
+        client.refill(now, rate, burst);
+
+        if let Some(rate) = rate {
+            if client.tokens < 1.0 {
+                let wait = ((1.0 - client.tokens) / rate).ceil() as u64;
+                return Err(Duration::from_secs(wait));
+            }
+        }
+        if resolves && max_in_flight.map(|max| client.in_flight >= max).unwrap_or(false) {
+            return Err(Duration::from_secs(IN_FLIGHT_RETRY));
+        }
+
+        if rate.is_some() {
+            client.tokens -= 1.0;
+        }
+        if resolves {
+            client.in_flight += 1;
+        }
+        Ok(Permit {
+            key: if resolves { Some(key) } else { None },
+            limiter: self.clone(),
+        })
+    }
+}
+
+impl LimiterState {
+    /// Returns the rate at which Clients earn tokens, and the most tokens they
+    /// can have.
+    fn rates(&self) -> (Option<f64>, Option<f64>) {
+        let rate = self.config.rate.map(f64::from);
+        let burst = self.config.burst.map(f64::from).or(rate);
+        (rate, burst)
+    }
+
+    /// Forgets the Clients that have no requests in flight and a full bucket,
+    /// since they're no different from new ones.
+    fn sweep(&mut self) {
+        let (rate, burst) = self.rates();
+        let now = Instant::now();
+        self.clients
+            .retain(|_, client| client.in_flight > 0 || !client.refill(now, rate, burst));
+    }
+}
+
+impl ClientState {
+    /// Adds the tokens earned since the last refill, returning whether the
+    /// bucket is now full.
+    fn refill(&mut self, now: Instant, rate: Option<f64>, burst: Option<f64>) -> bool {
+        let (rate, burst) = match (rate, burst) {
+            (Some(rate), Some(burst)) => (rate, burst),
+            _ => return true,
+        };
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.tokens >= burst
+    }
+}
+
+/// Permission to handle a request. If the request counts against the
+/// Client's requests in flight, it stops counting when this is dropped.
+#[derive(Debug)]
+pub struct Permit {
+    key: Option<ClientKey>,
+    limiter: Limiter,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(ref key) = self.key {
+            if let Some(client) = self.limiter.0.borrow_mut().clients.get_mut(key) {
+                client.in_flight -= 1;
+            }
+        }
+    }
+}
+
+#[test]
+fn limiter_test() {
+    use tokio_core::reactor::Core;
+
+    let core = Core::new().unwrap();
+    let limiter = Limiter::new(
+        LimitConfig {
+            by: LimitBy::Session,
+            burst: Some(2),
+            max_in_flight: Some(1),
+            rate: Some(1),
+        },
+        &core.handle(),
+    ).unwrap();
+    let remote = "127.0.0.1:1234".parse().unwrap();
+
+    let permit = limiter.admit(remote, Some("a".to_owned()), true).unwrap();
+    assert_eq!(
+        limiter.admit(remote, Some("a".to_owned()), true).unwrap_err(),
+        Duration::from_secs(IN_FLIGHT_RETRY)
+    );
+    drop(permit);
+    limiter.admit(remote, Some("a".to_owned()), false).unwrap();
+    assert_eq!(
+        limiter.admit(remote, Some("a".to_owned()), false).unwrap_err(),
+        Duration::from_secs(1)
+    );
+
+    // Other sessions from the same address are limited separately.
+    limiter.admit(remote, Some("b".to_owned()), true).unwrap();
+    limiter.admit(remote, None, true).unwrap();
+
+    // Clients with nothing in flight and a full bucket are forgotten.
+    let limiter = Limiter::new(
+        LimitConfig {
+            by: LimitBy::Session,
+            burst: None,
+            max_in_flight: Some(1),
+            rate: None,
+        },
+        &core.handle(),
+    ).unwrap();
+    let _permit = limiter.admit(remote, Some("a".to_owned()), true).unwrap();
+    drop(limiter.admit(remote, Some("b".to_owned()), true).unwrap());
+    limiter.0.borrow_mut().sweep();
+    assert_eq!(limiter.0.borrow().clients.len(), 1);
+}
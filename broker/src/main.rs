@@ -1,5 +1,6 @@
 #[macro_use]
 extern crate clap;
+extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate monto3_broker;
@@ -7,11 +8,17 @@ extern crate pretty_logger;
 extern crate tokio_core;
 extern crate void;
 
+use std::net::{IpAddr, Ipv4Addr};
+use std::process::exit;
+
+use futures::Future;
+use futures::sync::oneshot;
 use tokio_core::reactor::Core;
 use void::{ResultVoidExt, unreachable};
 
 use monto3_broker::Broker;
 use monto3_broker::config::Config;
+use monto3_broker::record::{read_session, replay_clients};
 
 fn main() {
     // Start logging.
@@ -24,12 +31,38 @@ fn main() {
     // Create the I/O loop.
     let mut core = Core::new().expect("Couldn't create event loop");
 
+    // Read the session to replay, if there is one.
+    let replay = config.broker.replay.as_ref().map(|path| {
+        read_session(path).expect("Couldn't read the session to replay")
+    });
     // Create the Broker and connect to services.
     let handle = core.handle();
-    let broker = core.run(Broker::new(config, handle)).expect(
+    let broker = core.run(Broker::new(config, handle.clone())).expect(
         "Couldn't initialize Broker",
     );
 
+    // If replaying a session, send the recorded Client requests to the Broker
+    // and report any that got different responses.
+    if let Some(records) = replay {
+        let (addr_send, addr_recv) = oneshot::channel();
+        let clients = addr_recv.then(move |addr| {
+            let addr = addr.expect("The Broker stopped before the session was replayed");
+            replay_clients(records, addr, &handle)
+        });
+        let serve = broker.serve_until(clients);
+        let mut addr = serve.local_addr();
+        if addr.ip().is_unspecified() {
+            addr.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        }
+        let _ = addr_send.send(addr);
+        let mismatches = core.run(serve).expect("Couldn't replay the session");
+        for mismatch in &mismatches {
+            error!("{}", mismatch);
+        }
+        info!("Replayed the session with {} mismatches", mismatches.len());
+        exit(if mismatches.is_empty() { 0 } else { 1 });
+    }
+
     // Run the Broker, listening for clients.
     let r = core.run(broker.serve_forever());
     unreachable(r.void_unwrap());
@@ -0,0 +1,374 @@
+//! Running Services as child processes of the Broker.
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::ToSocketAddrs;
+use std::process::{Child, Command};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::future::{err, loop_fn, ok, Loop};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+
+use Broker;
+use config::ServiceConfig;
+use service::Service;
+
+/// How often to check whether a Service has exited or started listening, in
+/// milliseconds.
+const POLL_INTERVAL: u64 = 100;
+
+/// How long a Service has to start listening after being started, in
+/// milliseconds.
+const STARTUP_TIMEOUT: u64 = 10_000;
+
+/// The shortest and longest times to wait before restarting a Service, in
+/// milliseconds. The wait doubles each time the Service exits, until it has
+/// run for `HEALTHY_AFTER` milliseconds.
+const MIN_BACKOFF: u64 = 500;
+const MAX_BACKOFF: u64 = 30_000;
+const HEALTHY_AFTER: u64 = 60_000;
+
+/// A Service run by the Broker. The process is killed when this is dropped.
+#[derive(Debug)]
+pub struct Process {
+    child: Option<Child>,
+    config: ServiceConfig,
+    failures: u32,
+    started: Instant,
+}
+
+impl Process {
+    /// Starts a Service with the command in its configuration.
+    pub fn spawn(config: ServiceConfig) -> Result<Process, IoError> {
+        let child = start(&config)?;
+        Ok(Process {
+            child: Some(child),
+            config,
+            failures: 0,
+            started: Instant::now(),
+        })
+    }
+
+    /// Returns a Future that resolves once the Service accepts connections on
+    /// its configured address, or fails if it doesn't within
+    /// `STARTUP_TIMEOUT`.
+    pub fn listening(&self, handle: &Handle) -> Box<Future<Item = (), Error = IoError>> {
+        let addr = match self.config.addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr,
+            Ok(None) => {
+                let msg = format!("{} didn't resolve to an address", self.config.addr);
+                return Box::new(err(IoError::new(IoErrorKind::InvalidInput, msg)));
+            }
+            Err(e) => return Box::new(err(e)),
+        };
+        let deadline = Instant::now() + Duration::from_millis(STARTUP_TIMEOUT);
+        let handle = handle.clone();
+        Box::new(loop_fn((), move |()| {
+            let handle = handle.clone();
+            TcpStream::connect(&addr, &handle).then(
+                move |r| -> Box<Future<Item = Loop<(), ()>, Error = IoError>> {
+                    match r {
+                        Ok(_) => Box::new(ok(Loop::Break(()))),
+                        Err(e) => if Instant::now() >= deadline {
+                            Box::new(err(e))
+                        } else {
+                            match Timeout::new(Duration::from_millis(POLL_INTERVAL), &handle) {
+                                Ok(timeout) => Box::new(timeout.map(|()| Loop::Continue(()))),
+                                Err(e) => Box::new(err(e)),
+                            }
+                        },
+                    }
+                },
+            )
+        }))
+    }
+
+    /// Returns a Future that resolves once the Service has exited.
+    fn exited(self, handle: &Handle) -> Box<Future<Item = Process, Error = IoError>> {
+        let handle = handle.clone();
+        Box::new(loop_fn(self, move |mut process| -> Box<Future<Item = _, Error = _>> {
+            let exited = match process.child {
+                Some(ref mut child) => match child.try_wait() {
+                    Ok(Some(status)) => {
+                        warn!("Service at {} exited with {}", process.config.addr, status);
+                        true
+                    }
+                    Ok(None) => false,
+                    Err(e) => {
+                        error!("Couldn't check on service at {}: {}", process.config.addr, e);
+                        true
+                    }
+                },
+                None => true,
+            };
+            if exited {
+                Box::new(ok(Loop::Break(process)))
+            } else {
+                match Timeout::new(Duration::from_millis(POLL_INTERVAL), &handle) {
+                    Ok(timeout) => Box::new(timeout.map(move |()| Loop::Continue(process))),
+                    Err(e) => Box::new(err(e)),
+                }
+            }
+        }))
+    }
+
+    /// Waits out the backoff, then starts the Service again, connects to it,
+    /// and replaces the Broker's connection to the old one. If it can't be
+    /// connected to, it's killed, so it will be restarted again.
+    fn restart(
+        mut self,
+        broker: Rc<RefCell<Broker>>,
+        handle: &Handle,
+    ) -> Box<Future<Item = Process, Error = IoError>> {
+        if self.started.elapsed() >= Duration::from_millis(HEALTHY_AFTER) {
+            self.failures = 0;
+        }
+        let backoff = min(MIN_BACKOFF << min(self.failures, 16), MAX_BACKOFF);
+        self.failures += 1;
+        info!("Restarting service at {} in {}ms", self.config.addr, backoff);
+
+        let timeout = match Timeout::new(Duration::from_millis(backoff), handle) {
+            Ok(timeout) => timeout,
+            Err(e) => return Box::new(err(e)),
+        };
+        let handle = handle.clone();
+        Box::new(timeout.and_then(move |()| -> Box<Future<Item = _, Error = _>> {
+            self.started = Instant::now();
+            match start(&self.config) {
+                Ok(child) => self.child = Some(child),
+                Err(e) => {
+                    error!("Couldn't start service at {}: {}", self.config.addr, e);
+                    self.child = None;
+                    return Box::new(ok(self));
+                }
+            }
+
+            let (config, extensions) = {
+                let broker = broker.borrow();
+                (broker.config.clone(), broker.extensions.clone())
+            };
+            let service_config = self.config.clone();
+            let connect = self.listening(&handle)
+                .map_err(|e| e.to_string())
+                .and_then(move |()| {
+                    Service::connect(config, service_config, &extensions, &handle)
+                        .map_err(|e| e.to_string())
+                });
+            Box::new(connect.then(move |r| {
+                match r {
+                    Ok(service) => broker.borrow_mut().replace_service(service),
+                    Err(e) => {
+                        error!("Couldn't connect to service at {}: {}", self.config.addr, e);
+                        self.kill();
+                    }
+                }
+                Ok(self)
+            }))
+        }))
+    }
+
+    /// Kills the process, if it's running.
+    fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Returns a Future that restarts a Service whenever it exits. It never
+/// resolves, except on an error from the event loop.
+pub(crate) fn supervise(
+    process: Process,
+    broker: Rc<RefCell<Broker>>,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = IoError>> {
+    let handle = handle.clone();
+    Box::new(loop_fn(process, move |process| {
+        let broker = broker.clone();
+        let handle = handle.clone();
+        process
+            .exited(&handle)
+            .and_then(move |process| process.restart(broker, &handle))
+            .map(Loop::Continue)
+    }))
+}
+
+/// Starts the command for a Service.
+fn start(config: &ServiceConfig) -> Result<Child, IoError> {
+    let (program, args) = match config.command.split_first() {
+        Some(command) => command,
+        None => {
+            let msg = format!("No command to run for service at {}", config.addr);
+            return Err(IoError::new(IoErrorKind::InvalidInput, msg));
+        }
+    };
+    info!("Starting service at {}: {:?}", config.addr, config.command);
+    let mut command = Command::new(program);
+    command.args(args).envs(&config.env);
+    if let Some(ref dir) = config.dir {
+        command.current_dir(dir);
+    }
+    command.spawn()
+}
+
+#[cfg(test)]
+fn test_config(addr: &str, command: &[&str]) -> ServiceConfig {
+    use std::collections::BTreeMap;
+
+    ServiceConfig {
+        addr: addr.to_owned(),
+        base: "/monto".to_owned(),
+        command: command.iter().map(|&s| s.to_owned()).collect(),
+        dir: None,
+        env: BTreeMap::new(),
+        scheme: "http".to_owned(),
+    }
+}
+
+#[test]
+fn exited_test() {
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let process = Process::spawn(test_config("127.0.0.1:0", &["sh", "-c", "exit 3"])).unwrap();
+    let mut process = core.run(process.exited(&core.handle())).unwrap();
+    let status = process.child.as_mut().unwrap().try_wait().unwrap();
+    assert_eq!(status.and_then(|s| s.code()), Some(3));
+}
+
+#[cfg(test)]
+fn test_child(env: &[(&str, String)]) -> ServiceConfig {
+    use std::env::current_exe;
+
+    let exe = current_exe().unwrap().display().to_string();
+    let mut config = test_config("127.0.0.1:0", &[&exe, "process::child", "--exact", "--quiet"]);
+    config.env = env.iter()
+        .map(|&(k, ref v)| (k.to_owned(), v.clone()))
+        .collect();
+    config
+}
+
+/// Not a test itself, but run by the other tests as a child process, by
+/// running the test binary again. If `MONTO3_TEST_STARTS` is set, a line is
+/// appended to the file it names. If `MONTO3_TEST_LISTEN` is set, the child
+/// listens on the address it names until it is killed; otherwise it exits.
+#[test]
+fn child() {
+    use std::env::var;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    if let Ok(path) = var("MONTO3_TEST_STARTS") {
+        let mut file = OpenOptions::new().append(true).create(true).open(path).unwrap();
+        file.write_all(b"started\n").unwrap();
+    }
+    if let Ok(addr) = var("MONTO3_TEST_LISTEN") {
+        let listener = TcpListener::bind(addr).unwrap();
+        for _ in listener.incoming() {}
+    }
+}
+
+/// Returns an address on localhost with a port nothing is listening on.
+#[cfg(test)]
+fn free_addr() -> String {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[test]
+fn listening_test() {
+    use tokio_core::reactor::Core;
+
+    let addr = free_addr();
+    let mut config = test_child(&[("MONTO3_TEST_LISTEN", addr.clone())]);
+    config.addr = addr;
+    let process = Process::spawn(config).unwrap();
+
+    let mut core = Core::new().unwrap();
+    core.run(process.listening(&core.handle())).unwrap();
+}
+
+#[cfg(test)]
+fn test_broker(core: &mut ::tokio_core::reactor::Core, process: Process) -> Broker {
+    use config::Config;
+
+    let mut config = Config::default();
+    config.net.addr = "127.0.0.1:0".parse().unwrap();
+    let mut broker = core.run(Broker::new(config, core.handle())).unwrap();
+    broker.processes.push(process);
+    broker
+}
+
+#[test]
+fn restart_test() {
+    use std::env::temp_dir;
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    use futures::future::{empty, Either};
+    use tokio_core::reactor::Core;
+
+    let starts = temp_dir().join(format!("monto3-restart-{}.txt", ::rand::random::<u64>()));
+    let config = test_child(&[("MONTO3_TEST_STARTS", starts.display().to_string())]);
+    let mut core = Core::new().unwrap();
+    let process = Process::spawn(config).unwrap();
+    let mut serve = test_broker(&mut core, process).serve_until(empty::<(), ()>());
+
+    let count = || {
+        let mut s = String::new();
+        let _ = File::open(&starts).and_then(|mut f| f.read_to_string(&mut s));
+        s.lines().count()
+    };
+    let start = Instant::now();
+    while count() < 2 {
+        assert!(start.elapsed() < Duration::from_secs(10), "the service was never restarted");
+        let timeout = Timeout::new(Duration::from_millis(50), &core.handle()).unwrap();
+        serve = match core.run(serve.select2(timeout)) {
+            Ok(Either::B((_, serve))) => serve,
+            _ => panic!("the broker stopped serving"),
+        };
+    }
+
+    drop(serve);
+    fs::remove_file(starts).unwrap();
+}
+
+#[test]
+fn kill_on_drop_test() {
+    use std::net::TcpStream as StdTcpStream;
+
+    use futures::future::{empty, Either};
+    use tokio_core::reactor::Core;
+
+    let addr = free_addr();
+    let mut config = test_child(&[("MONTO3_TEST_LISTEN", addr.clone())]);
+    config.addr = addr.clone();
+    let mut core = Core::new().unwrap();
+    let process = Process::spawn(config).unwrap();
+    core.run(process.listening(&core.handle())).unwrap();
+
+    let serve = test_broker(&mut core, process).serve_until(empty::<(), ()>());
+    let timeout = Timeout::new(Duration::from_millis(200), &core.handle()).unwrap();
+    let serve = match core.run(serve.select2(timeout)) {
+        Ok(Either::B((_, serve))) => serve,
+        _ => panic!("the broker stopped serving"),
+    };
+    assert!(StdTcpStream::connect(&addr).is_ok());
+
+    drop(serve);
+    assert!(StdTcpStream::connect(&addr).is_err());
+}
@@ -0,0 +1,539 @@
+//! Recording the traffic through the Broker to a session file, and replaying
+//! it.
+//!
+//! A session file has one JSON-encoded `Record` per line. When a session is
+//! replayed, the Broker stands in for the recorded Services, answering each
+//! `BrokerRequest` with the response recorded for it, and the recorded Client
+//! requests are sent to the Broker again, so that its responses can be
+//! compared to the recorded ones.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, LineWriter, Result as IoResult,
+              Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::rc::Rc;
+
+use futures::{Future, Sink, Stream};
+use futures::future::{ok, Either};
+use futures::sync::oneshot::channel as oneshot;
+use futures::stream::iter_ok;
+use hyper::{Body, Client as HyperClient, Error as HyperError, Headers, Method, Request,
+            Response};
+use hyper::header::{ContentEncoding, ContentType, Header};
+use serde_json;
+use serde_json::Value;
+use tokio_core::reactor::Handle;
+
+use monto3_common::encoding::decompress;
+use monto3_common::format::Format;
+use monto3_common::headers::MontoSession;
+use monto3_common::messages::{Identifier, ProductDescriptor};
+use monto3_service::messages::{BrokerRequest, ServiceErrors, ServiceNegotiation, ServiceProduct};
+
+use service::{RequestError, RequestErrorKind};
+
+/// An entry in a session file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(content = "value", rename_all = "snake_case", tag = "type")]
+pub enum Record {
+    /// A Service the Broker connected to.
+    Service(ServiceNegotiation),
+
+    /// A request a Client sent to the Broker, and the Broker's response.
+    ClientExchange(ClientExchange),
+
+    /// A request the Broker sent to a Service, and the Service's response.
+    ServiceExchange(ServiceExchange),
+}
+
+/// A request a Client sent to the Broker, and the Broker's response.
+///
+/// Bodies are stored decompressed and converted to JSON, regardless of the
+/// format they were sent in. Bodies that aren't in a supported format are
+/// stored as strings.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClientExchange {
+    /// The method of the request.
+    pub method: String,
+
+    /// The path and query of the request.
+    pub uri: String,
+
+    /// The headers of the request, except those describing how the body was
+    /// transferred.
+    pub headers: BTreeMap<String, String>,
+
+    /// The body of the request.
+    pub body: Value,
+
+    /// The status of the response, or 0 if routing the request failed.
+    pub status: u16,
+
+    /// The session token given to the Client in the response, if any.
+    pub session: Option<String>,
+
+    /// The body of the response, or the error if routing the request failed.
+    pub response: Value,
+}
+
+/// A request the Broker sent to a Service, and the Service's response.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ServiceExchange {
+    /// The Service the request was sent to.
+    pub service: Identifier,
+
+    /// The request.
+    pub request: BrokerRequest,
+
+    /// The response.
+    pub response: ServiceResponse,
+}
+
+/// The outcome of a request to a Service.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(content = "value", rename_all = "snake_case", tag = "type")]
+pub enum ServiceResponse {
+    /// The Service provided the product.
+    Product(ServiceProduct),
+
+    /// The Service does not expose the product.
+    NotExposed(ProductDescriptor),
+
+    /// The Service sent errors.
+    Errors(ServiceErrors),
+
+    /// The request failed for another reason, such as a network error.
+    Failed(String),
+}
+
+impl<'a> From<&'a Result<ServiceProduct, RequestError>> for ServiceResponse {
+    fn from(result: &'a Result<ServiceProduct, RequestError>) -> ServiceResponse {
+        match *result {
+            Ok(ref sp) => ServiceResponse::Product(sp.clone()),
+            Err(RequestError(RequestErrorKind::NotExposed(ref pd), _)) => {
+                ServiceResponse::NotExposed(pd.clone())
+            }
+            Err(RequestError(RequestErrorKind::ServiceErrors(ref ses), _)) => {
+                ServiceResponse::Errors(ses.clone())
+            }
+            Err(ref e) => ServiceResponse::Failed(e.to_string()),
+        }
+    }
+}
+
+impl From<ServiceResponse> for Result<ServiceProduct, RequestError> {
+    fn from(response: ServiceResponse) -> Result<ServiceProduct, RequestError> {
+        match response {
+            ServiceResponse::Product(sp) => Ok(sp),
+            ServiceResponse::NotExposed(pd) => Err(RequestErrorKind::NotExposed(pd).into()),
+            ServiceResponse::Errors(ses) => Err(RequestErrorKind::ServiceErrors(ses).into()),
+            ServiceResponse::Failed(e) => Err(RequestErrorKind::Recorded(e).into()),
+        }
+    }
+}
+
+/// Writes records to a session file.
+#[derive(Clone)]
+pub struct Recorder(Rc<RefCell<LineWriter<File>>>);
+
+impl Recorder {
+    /// Creates a session file at the given path, replacing any existing file.
+    pub fn create<P: AsRef<Path>>(path: P) -> IoResult<Recorder> {
+        let file = File::create(path)?;
+        Ok(Recorder(Rc::new(RefCell::new(LineWriter::new(file)))))
+    }
+
+    /// Writes a record to the session file. Errors are logged rather than
+    /// returned, so recording never interferes with the Broker's operation.
+    pub fn record(&self, record: &Record) {
+        let mut file = self.0.borrow_mut();
+        let result = serde_json::to_writer(&mut *file, record)
+            .map_err(IoError::from)
+            .and_then(|()| file.write_all(b"\n"));
+        if let Err(err) = result {
+            error!("Couldn't write to the session file: {}", err);
+        }
+    }
+
+    /// Routes a request from a Client with the given function, recording the
+    /// request and the response. The bodies are passed on as they are read,
+    /// and the exchange is recorded once both have ended. If routing the
+    /// request fails, the exchange is recorded with the error instead of a
+    /// response.
+    pub(crate) fn client<F>(
+        &self,
+        req: Request,
+        handle: &Handle,
+        route: F,
+    ) -> Box<Future<Item = Response, Error = HyperError>>
+    where
+        F: FnOnce(Request) -> Box<Future<Item = Response, Error = HyperError>> + 'static,
+    {
+        let recorder = self.clone();
+        let handle = handle.clone();
+        let (method, uri, version, headers, body) = req.deconstruct();
+        let (body, request_body) = tee(body, &handle);
+        let mut exchange = ClientExchange {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            headers: recorded_headers(&headers),
+            body: Value::Null,
+            status: 0,
+            session: None,
+            response: Value::Null,
+        };
+        let request_headers = headers.clone();
+
+        let mut req = Request::new(method, uri);
+        req.set_version(version);
+        *req.headers_mut() = headers;
+        req.set_body(body);
+        Box::new(route(req).then(move |result| {
+            let res = match result {
+                Ok(res) => res,
+                Err(err) => {
+                    exchange.response = Value::String(err.to_string());
+                    handle.spawn(request_body.then(move |body| {
+                        if let Ok(body) = body {
+                            exchange.body = body_value(&body, &request_headers);
+                        }
+                        recorder.record(&Record::ClientExchange(exchange));
+                        Ok(())
+                    }));
+                    return Err(err);
+                }
+            };
+
+            let status = res.status();
+            let headers = res.headers().clone();
+            exchange.status = status.into();
+            exchange.session = headers
+                .get()
+                .map(|&MontoSession(ref token)| token.clone());
+            let (body, response_body) = tee(res.body(), &handle);
+            let response_headers = headers.clone();
+            handle.spawn(request_body.join(response_body).then(move |bodies| {
+                match bodies {
+                    Ok((body, response)) => {
+                        exchange.body = body_value(&body, &request_headers);
+                        exchange.response = body_value(&response, &response_headers);
+                        recorder.record(&Record::ClientExchange(exchange));
+                    }
+                    Err(err) => {
+                        error!("Couldn't record {} {}: {}", exchange.method, exchange.uri, err)
+                    }
+                }
+                Ok(())
+            }));
+            Ok(Response::new()
+                .with_status(status)
+                .with_headers(headers)
+                .with_body(body))
+        }))
+    }
+}
+
+impl Debug for Recorder {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_tuple("Recorder").finish()
+    }
+}
+
+/// Copies a body into a new one chunk by chunk as it is read, so it can be
+/// passed on without waiting for all of it. The returned Future resolves to
+/// the whole body once it has ended. The body is read to the end even if the
+/// copy is dropped.
+fn tee(body: Body, handle: &Handle) -> (Body, Box<Future<Item = Vec<u8>, Error = HyperError>>) {
+    let (sender, copy) = Body::pair();
+    let (done, whole) = oneshot();
+    let forward = body.fold((Some(sender), Vec::new()), |(sender, mut buf), chunk| {
+        buf.extend_from_slice(&chunk);
+        let sent = match sender {
+            Some(sender) => {
+                Either::A(sender.send(Ok(chunk)).then(|r| Ok::<_, HyperError>(r.ok())))
+            }
+            None => Either::B(ok(None)),
+        };
+        sent.map(move |sender| (sender, buf))
+    });
+    handle.spawn(forward.then(move |r| {
+        let _ = done.send(r.map(|(_, buf)| buf));
+        Ok(())
+    }));
+    (copy, Box::new(whole.then(|r| r.unwrap_or(Err(HyperError::Incomplete)))))
+}
+
+/// Reads the records from a session file.
+pub fn read_session<P: AsRef<Path>>(path: P) -> IoResult<Vec<Record>> {
+    let file = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in file.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            let record = serde_json::from_str(&line)
+                .map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// The recorded responses of a Service, used to stand in for it when a
+/// session is replayed.
+pub(crate) struct ServiceReplay {
+    exchanges: Vec<(Value, ServiceResponse)>,
+}
+
+impl ServiceReplay {
+    /// Collects the responses recorded for the Service with the given
+    /// identifier.
+    pub fn new(service: &Identifier, records: &[Record]) -> ServiceReplay {
+        let exchanges = records
+            .iter()
+            .filter_map(|record| match *record {
+                Record::ServiceExchange(ref exchange) if &exchange.service == service => {
+                    serde_json::to_value(&exchange.request)
+                        .ok()
+                        .map(|request| (request, exchange.response.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        ServiceReplay { exchanges }
+    }
+
+    /// Responds to a request with the response first recorded for an
+    /// identical request.
+    pub fn respond(&self, br: &BrokerRequest) -> Result<ServiceProduct, RequestError> {
+        let request = serde_json::to_value(br)?;
+        match self.exchanges.iter().find(|&&(ref r, _)| r == &request) {
+            Some(&(_, ref response)) => response.clone().into(),
+            None => Err(RequestErrorKind::Recorded(
+                "No response was recorded for this request".to_owned(),
+            ).into()),
+        }
+    }
+}
+
+/// A recorded Client request whose response differed when it was replayed.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// The recorded request and response.
+    pub exchange: ClientExchange,
+
+    /// The status of the response when the request was replayed.
+    pub status: u16,
+
+    /// The body of the response when the request was replayed.
+    pub response: Value,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(
+            fmt,
+            "{} {}: expected {} {}, got {} {}",
+            self.exchange.method,
+            self.exchange.uri,
+            self.exchange.status,
+            self.exchange.response,
+            self.status,
+            self.response
+        )
+    }
+}
+
+/// Sends the Client requests recorded in a session to the Broker at the given
+/// address, in order, returning those whose responses differ from the
+/// recorded ones.
+///
+/// Session tokens given out by the Broker are mapped to the ones given out
+/// when the session was recorded.
+pub fn replay_clients(
+    records: Vec<Record>,
+    addr: SocketAddr,
+    handle: &Handle,
+) -> Box<Future<Item = Vec<Mismatch>, Error = HyperError>> {
+    let http = HyperClient::new(handle);
+    let exchanges = records.into_iter().filter_map(|record| match record {
+        Record::ClientExchange(exchange) => Some(exchange),
+        _ => None,
+    });
+    let start = (Vec::new(), BTreeMap::<String, String>::new());
+    Box::new(
+        iter_ok(exchanges)
+            .fold(start, move |(mut mismatches, mut sessions), exchange| {
+                let req = match replay_request(&exchange, addr, &sessions) {
+                    Some(req) => req,
+                    None => {
+                        warn!("Couldn't replay {} {}", exchange.method, exchange.uri);
+                        return Box::new(ok((mismatches, sessions)))
+                            as Box<Future<Item = _, Error = HyperError>>;
+                    }
+                };
+                let response = http.request(req).and_then(|res| {
+                    let status = u16::from(res.status());
+                    let headers = res.headers().clone();
+                    res.body()
+                        .concat2()
+                        .map(move |body| (status, headers, body))
+                });
+                Box::new(response.then(move |r| {
+                    // An exchange recorded with status 0 failed without a
+                    // response, so it should fail again.
+                    let (status, response) = match r {
+                        Ok((status, headers, body)) => {
+                            let session =
+                                headers.get().map(|&MontoSession(ref token)| token.clone());
+                            if let (Some(old), Some(new)) = (exchange.session.clone(), session) {
+                                sessions.insert(old, new);
+                            }
+                            (status, body_value(&body, &headers))
+                        }
+                        Err(err) => (0, Value::String(err.to_string())),
+                    };
+                    let matches = if exchange.status == 0 {
+                        status == 0
+                    } else {
+                        status == exchange.status && response == exchange.response
+                    };
+                    if !matches {
+                        mismatches.push(Mismatch {
+                            exchange,
+                            status,
+                            response,
+                        });
+                    }
+                    Ok((mismatches, sessions))
+                }))
+            })
+            .map(|(mismatches, _)| mismatches),
+    )
+}
+
+/// Rebuilds a recorded request, to be sent to the Broker at the given address.
+fn replay_request(
+    exchange: &ClientExchange,
+    addr: SocketAddr,
+    sessions: &BTreeMap<String, String>,
+) -> Option<Request> {
+    let method = exchange.method.parse::<Method>().ok()?;
+    let uri = format!("http://{}{}", addr, exchange.uri).parse().ok()?;
+    let mut req = Request::new(method, uri);
+    for (name, value) in &exchange.headers {
+        let value = if name.eq_ignore_ascii_case(MontoSession::header_name()) {
+            sessions.get(value).unwrap_or(value).clone()
+        } else {
+            value.clone()
+        };
+        req.headers_mut().set_raw(name.clone(), value);
+    }
+    let body = match exchange.body {
+        Value::Null => Vec::new(),
+        Value::String(ref s) if is_text(req.headers()) => s.clone().into_bytes(),
+        ref body => {
+            req.headers_mut().set(ContentType::json());
+            serde_json::to_vec(body).ok()?
+        }
+    };
+    req.set_body(body);
+    Some(req)
+}
+
+/// Returns the headers of a request that are recorded.
+fn recorded_headers(headers: &Headers) -> BTreeMap<String, String> {
+    const SKIPPED: &[&str] = &["accept-encoding", "content-encoding", "content-length", "host"];
+    headers
+        .iter()
+        .filter(|h| !SKIPPED.iter().any(|s| h.name().eq_ignore_ascii_case(s)))
+        .map(|h| (h.name().to_owned(), h.value_string()))
+        .collect()
+}
+
+/// Converts a body to JSON, as it is stored in a session file.
+fn body_value(body: &[u8], headers: &Headers) -> Value {
+    let body = match decompress(body.to_vec(), headers.get::<ContentEncoding>()) {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Couldn't decompress a recorded body: {}", err);
+            body.to_vec()
+        }
+    };
+    if body.is_empty() {
+        Value::Null
+    } else if is_text(headers) {
+        Value::String(String::from_utf8_lossy(&body).into_owned())
+    } else {
+        Format::from_content_type(headers.get())
+            .deserialize(&body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()))
+    }
+}
+
+/// Returns whether a body is plain text, rather than a serialized message.
+fn is_text(headers: &Headers) -> bool {
+    headers
+        .get::<ContentType>()
+        .map(|&ContentType(ref mime)| mime.type_() == ::mime::TEXT)
+        .unwrap_or(false)
+}
+
+#[test]
+fn service_replay_test() {
+    use monto3_common::messages::{Language, Product, ProductIdentifier, ProductName};
+
+    let request = BrokerRequest {
+        request: ProductIdentifier {
+            name: ProductName::Highlighting,
+            language: Language::Text,
+            path: "/tmp/foo.txt".to_owned(),
+        },
+        products: Vec::new(),
+    };
+    let exchange = ServiceExchange {
+        service: "com.example.foo".parse().unwrap(),
+        request: request.clone(),
+        response: ServiceResponse::Product(ServiceProduct {
+            product: Product {
+                name: ProductName::Highlighting,
+                language: Language::Text,
+                path: "/tmp/foo.txt".to_owned(),
+                value: Value::Array(Vec::new()),
+            },
+            notices: Vec::new(),
+        }),
+    };
+    let line = serde_json::to_string(&Record::ServiceExchange(exchange)).unwrap();
+    let record: Record = serde_json::from_str(&line).unwrap();
+
+    let replay = ServiceReplay::new(&"com.example.foo".parse().unwrap(), &[record]);
+    let sp = replay.respond(&request).unwrap();
+    assert_eq!(sp.product.value, Value::Array(Vec::new()));
+
+    let mut other = request;
+    other.request.path = "/tmp/bar.txt".to_owned();
+    assert!(replay.respond(&other).is_err());
+}
+
+#[test]
+fn tee_test() {
+    use hyper::Chunk;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let (send, body) = Body::pair();
+    let (copy, whole) = tee(body, &core.handle());
+
+    // The first chunk is passed on before the body ends.
+    let send = core.run(send.send(Ok(Chunk::from("foo")))).unwrap();
+    let (first, copy) = core.run(copy.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(first.unwrap().as_ref(), b"foo");
+
+    core.run(send.send(Ok(Chunk::from("bar")))).unwrap();
+    assert_eq!(core.run(copy.concat2()).unwrap().as_ref(), b"bar");
+    assert_eq!(core.run(whole).unwrap(), b"foobar");
+}
@@ -1,60 +1,115 @@
 use std::cell::RefCell;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::mpsc::channel;
+use std::thread;
 use std::time::Duration;
 
-use notify::{DebouncedEvent, Error as NotifyError, RecommendedWatcher, RecursiveMode,
+use futures::Future;
+use futures::future::{join_all, Shared};
+use futures::sync::mpsc::unbounded;
+use futures::sync::oneshot::{channel as oneshot, Receiver, Sender};
+use futures_cpupool::{CpuFuture, CpuPool};
+use globset::GlobSet;
+use notify::{DebouncedEvent, PollWatcher, RecommendedWatcher, RecursiveMode,
              Watcher as NotifyWatcher};
 use serde_json::Value;
 use tokio_core::reactor::Handle;
 
-use monto3_common::messages::{Product, ProductDescriptor, ProductIdentifier};
+use monto3_common::messages::{Identifier, Product, ProductDescriptor, ProductIdentifier,
+                              ProductName};
+use monto3_common::paths;
 
+use NewBrokerError;
+use config::BrokerConfig;
 use resolve::watcher::Watcher;
 
+/// The fingerprint of a watched path, as computed by `fingerprint` on the
+/// thread pool.
+type Fingerprint = Shared<CpuFuture<Option<u64>, ()>>;
+
+/// The key for a product at a path. Sources are shared by every Service, so
+/// they have no Service; other products are kept separately for each Service
+/// that produced them.
+type ProductKey = (Option<Identifier>, ProductDescriptor);
+
 /// A cache for products.
 pub struct Cache {
-    products: BTreeMap<PathBuf, BTreeMap<ProductDescriptor, Value>>,
+    changes: BTreeMap<PathBuf, Vec<Sender<()>>>,
+    products: BTreeMap<PathBuf, BTreeMap<ProductKey, Value>>,
+    versions: BTreeMap<PathBuf, u64>,
+    pool: CpuPool,
+    poll: GlobSet,
+    poll_watcher: Option<PollWatcher>,
     watcher: RecommendedWatcher,
-    watching: BTreeSet<PathBuf>,
-    watch_chan: Receiver<DebouncedEvent>,
+    watching: BTreeMap<PathBuf, Fingerprint>,
 }
 
 impl Cache {
-    /// Creates a new cache.
-    pub fn new(handle: &Handle) -> Result<Rc<RefCell<Cache>>, NotifyError> {
+    /// Creates a new cache. Paths matching the `poll` globs in the config are
+    /// watched for changes by polling, and others with the operating system's
+    /// notifications. Watched paths are read on the given thread pool.
+    pub fn new(
+        config: &BrokerConfig,
+        pool: CpuPool,
+        handle: &Handle,
+    ) -> Result<Rc<RefCell<Cache>>, NewBrokerError> {
         let (send, recv) = channel();
+        let poll = config.poll_globs()?;
+        let poll_watcher = if config.poll.is_empty() {
+            None
+        } else {
+            let interval = Duration::from_millis(config.poll_interval);
+            Some(PollWatcher::new(send.clone(), interval)?)
+        };
         let watcher = RecommendedWatcher::new(send, Duration::from_millis(100))?;
+
+        // notify sends events over a std channel, which can't wake up a task,
+        // so they're forwarded to one that can from another thread.
+        let (events_send, events) = unbounded::<DebouncedEvent>();
+        thread::spawn(move || {
+            for ev in recv {
+                if events_send.unbounded_send(ev).is_err() {
+                    break;
+                }
+            }
+        });
+
         let cache = Rc::new(RefCell::new(Cache {
+            changes: BTreeMap::new(),
             products: BTreeMap::new(),
+            versions: BTreeMap::new(),
+            pool,
+            poll,
+            poll_watcher,
             watcher: watcher,
-            watching: BTreeSet::new(),
-            watch_chan: recv,
+            watching: BTreeMap::new(),
         }));
-        handle.spawn(Watcher::new(cache.clone()));
+        handle.spawn(Watcher::new(cache.clone(), events, handle.clone()));
         Ok(cache)
     }
 
-    /// Returns the next event received by the FS watcher.
-    pub(crate) fn event(&self) -> Option<DebouncedEvent> {
-        match self.watch_chan.try_recv() {
-            Ok(ev) => Some(ev),
-            Err(TryRecvError::Disconnected) => {
-                error!(
-                    "The filesystem watcher died; https://twitter.com/rob_pike/status/447202124753952768"
-                );
-                None
-            }
-            Err(TryRecvError::Empty) => None,
-        }
+    /// Returns the thread pool watched paths are read on, which other
+    /// blocking work can share.
+    pub fn pool(&self) -> CpuPool {
+        self.pool.clone()
     }
 
-    /// Adds a product to the cache, replacing any other product that was
-    /// previously present.
-    pub fn add(&mut self, product: Product) {
+    /// Adds a product produced by the given Service to the cache, replacing
+    /// any other product that was previously present.
+    ///
+    /// If the product is a source that differs from the cached one, the
+    /// version of the source at its path is incremented, and the other
+    /// products at its path are evicted, since they may have been computed
+    /// from the old version. If it replaces a different source, the Futures
+    /// returned by `changed` for its path are resolved.
+    pub fn add(&mut self, si: Identifier, product: Product) {
         let Product {
             name,
             language,
@@ -63,58 +118,362 @@ impl Cache {
         } = product;
         info!("Added to cache: {} {} {}", name, language, path);
 
-        let desc = ProductDescriptor { name, language };
-        let path = PathBuf::from(path);
-        self.products
+        let file = paths::file_path(&path);
+        let path = key(&path);
+        let desc = product_key(si, ProductDescriptor { name, language });
+        let products = self.products
             .entry(path.clone())
-            .or_insert_with(BTreeMap::new)
-            .insert(desc, value);
-        if self.watching.insert(path.clone()) {
-            if let Err(err) = self.watcher.watch(path, RecursiveMode::Recursive) {
+            .or_insert_with(BTreeMap::new);
+        let mut replaced = false;
+        if desc.1.name == ProductName::Source && products.get(&desc) != Some(&value) {
+            *self.versions.entry(path.clone()).or_insert(0) += 1;
+            replaced = products.contains_key(&desc);
+            products.clear();
+        }
+        products.insert(desc, value);
+        if replaced {
+            self.notify_changed(&path);
+        }
+
+        // Documents that aren't files only change when a Client sends them.
+        let file = match file {
+            Some(file) => file,
+            None => return,
+        };
+        if !self.watching.contains_key(&file) {
+            let fingerprint = self.fingerprint(file.clone()).shared();
+            self.watching.insert(file.clone(), fingerprint);
+            let result = match self.poll_watcher {
+                Some(ref mut poll_watcher) if self.poll.is_match(&file) => {
+                    poll_watcher.watch(file, RecursiveMode::Recursive)
+                }
+                _ => self.watcher.watch(file, RecursiveMode::Recursive),
+            };
+            if let Err(err) = result {
                 error!("{}", err);
             }
         }
     }
 
+    /// Returns the version of the source at the given path.
+    ///
+    /// Versions are not reset when a path is evicted, so a version number is
+    /// never reused for a different source at the same path.
+    pub fn source_version(&self, path: &str) -> Option<u64> {
+        self.versions.get(&key(path)).cloned()
+    }
+
+    /// Returns the version of the source at the given path, if a source is
+    /// currently cached there. Unlike `source_version`, this is `None` once
+    /// the path has been evicted.
+    pub fn cached_source_version(&self, path: &str) -> Option<u64> {
+        let products = self.products.get(&key(path))?;
+        if products.keys().any(|&(_, ref pd)| pd.name == ProductName::Source) {
+            self.source_version(path)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a Future that resolves when the products at the given path
+    /// become stale, either because a new version of the source was added or
+    /// because the path was evicted.
+    pub fn changed(&mut self, path: &str) -> Receiver<()> {
+        let (send, recv) = oneshot();
+        let senders = self.changes
+            .entry(key(path))
+            .or_insert_with(Vec::new);
+        senders.retain(|send| !send.is_canceled());
+        senders.push(send);
+        recv
+    }
+
+    /// Resolves the Futures returned by `changed` for a path.
+    fn notify_changed(&mut self, path: &Path) {
+        for send in self.changes.remove(path).unwrap_or_default() {
+            let _ = send.send(());
+        }
+    }
+
     /// Removes all products with the given path from the cache.
     pub fn evict_by_path(&mut self, path: PathBuf) {
         let _ = self.products.remove(&path);
-        if self.watching.remove(&path) {
-            if let Err(err) = self.watcher.unwatch(path) {
+        self.notify_changed(&path);
+        if self.watching.remove(&path).is_some() {
+            let result = match self.poll_watcher {
+                Some(ref mut poll_watcher) if self.poll.is_match(&path) => {
+                    poll_watcher.unwatch(path)
+                }
+                _ => self.watcher.unwatch(path),
+            };
+            if let Err(err) = result {
                 error!("{}", err);
             }
         }
     }
 
-    /// Retrieves a product from the cache.
-    pub fn get(&self, pi: ProductIdentifier) -> Option<Product> {
-        info!("Cache request for {:?}", pi);
-
-        let path = PathBuf::from(&pi.path);
-        self.products.get(&path).and_then(move |m| {
-            let ProductIdentifier {
-                language,
-                name,
-                path,
-            } = pi;
-            let pd = ProductDescriptor { language, name };
-            m.get(&pd).map(move |value| {
-                Product {
-                    language: pd.language,
-                    name: pd.name,
-                    path: path,
-                    value: value.clone(),
-                }
-            })
+    /// Evicts the products for a path that was reported as changed, and those
+    /// for its ancestors, if their contents on disk are actually different
+    /// from when they were added to the cache. This keeps the cache intact
+    /// when e.g. a file is `touch`ed, or rewritten with the same contents.
+    ///
+    /// A change to a `.gitignore` or `.ignore` file always evicts the
+    /// directory containing it, since the directory's listing may change.
+    ///
+    /// The contents are read on the thread pool; the returned Future resolves
+    /// once they have been checked.
+    pub fn revalidate(
+        cache: &Rc<RefCell<Cache>>,
+        path: PathBuf,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let is_ignore_file = path.file_name()
+            .map(|name| name == ".gitignore" || name == ".ignore")
+            .unwrap_or(false);
+        let mut path = path;
+        if is_ignore_file && path.pop() {
+            cache.borrow_mut().evict_with_ancestors(path.clone());
+        }
+        let mut paths = vec![path.clone()];
+        while path.pop() {
+            paths.push(path.clone());
+        }
+        Cache::check(cache, paths)
+    }
+
+    /// Revalidates every watched path, as with `revalidate`. This is used when
+    /// the watcher reports that it may have missed events.
+    pub fn revalidate_all(cache: &Rc<RefCell<Cache>>) -> Box<Future<Item = (), Error = ()>> {
+        let paths = cache.borrow().watching.keys().cloned().collect();
+        Cache::check(cache, paths)
+    }
+
+    /// Fingerprints those of the given paths that are watched, evicting the
+    /// ones whose contents have changed since their products were added to
+    /// the cache.
+    fn check(
+        cache: &Rc<RefCell<Cache>>,
+        paths: Vec<PathBuf>,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let checks = {
+            let this = cache.borrow();
+            paths
+                .into_iter()
+                .filter_map(|path| {
+                    let old = this.watching.get(&path)?.clone().map(|fp| *fp).map_err(|_| ());
+                    let new = this.fingerprint(path.clone());
+                    let cache = cache.clone();
+                    Some(old.join(new).then(move |r| {
+                        match r {
+                            Ok((old, new)) if old == new => {}
+                            _ => cache.borrow_mut().evict_with_ancestors(path),
+                        }
+                        Ok(())
+                    }))
+                })
+                .collect::<Vec<_>>()
+        };
+        Box::new(join_all(checks).map(|_| ()))
+    }
+
+    /// Starts fingerprinting a path on the thread pool.
+    fn fingerprint(&self, path: PathBuf) -> CpuFuture<Option<u64>, ()> {
+        self.pool.spawn_fn(move || Ok(fingerprint(&path)))
+    }
+
+    /// Evicts the products for a path and all its ancestors.
+    fn evict_with_ancestors(&mut self, mut path: PathBuf) {
+        info!("Evicting path {} from cache", path.display());
+        self.evict_by_path(path.clone());
+        while path.pop() {
+            info!("Evicting path {} from cache", path.display());
+            self.evict_by_path(path.clone());
+        }
+    }
+
+    /// Retrieves a product produced by the given Service from the cache.
+    pub fn get(&self, si: &Identifier, pi: ProductIdentifier) -> Option<Product> {
+        info!("Cache request for {:?} from {}", pi, si);
+
+        let ProductIdentifier {
+            language,
+            name,
+            path,
+        } = pi;
+        let (si, pd) = product_key(si.clone(), ProductDescriptor { language, name });
+        let value = self.products.get(&key(&path))?.get(&(si, pd.clone()))?;
+        Some(Product {
+            language: pd.language,
+            name: pd.name,
+            path,
+            value: value.clone(),
         })
     }
 }
 
+/// Returns the key for a product produced by the given Service.
+fn product_key(si: Identifier, pd: ProductDescriptor) -> ProductKey {
+    if pd.name == ProductName::Source {
+        (None, pd)
+    } else {
+        (Some(si), pd)
+    }
+}
+
+/// Returns the key for the products at a path, so that paths referring to the
+/// same file share products.
+fn key(path: &str) -> PathBuf {
+    PathBuf::from(paths::normalize(path))
+}
+
+/// Hashes the contents of a file, or the names and types of the entries of a
+/// directory. Returns `None` if the path can't be read.
+fn fingerprint(path: &Path) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    if path.is_dir() {
+        let mut entries = fs::read_dir(path)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| (entry.file_name(), entry.path().is_dir()))
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries.hash(&mut hasher);
+    } else {
+        let mut buf = Vec::new();
+        File::open(path).ok()?.read_to_end(&mut buf).ok()?;
+        buf.hash(&mut hasher);
+    }
+    Some(hasher.finish())
+}
+
 impl Debug for Cache {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
         fmt.debug_struct("Cache")
             .field("products", &self.products)
-            .field("watching", &self.watching)
+            .field("versions", &self.versions)
+            .field("watching", &self.watching.keys().collect::<Vec<_>>())
             .finish()
     }
 }
+
+#[test]
+fn revalidate_test() {
+    use std::env::temp_dir;
+    use std::io::Write;
+
+    use tokio_core::reactor::Core;
+
+    use monto3_common::messages::Language;
+
+    let mut core = Core::new().unwrap();
+    let pool = CpuPool::new(1);
+    let cache = Cache::new(&BrokerConfig::default(), pool, &core.handle()).unwrap();
+
+    let path = temp_dir().join(format!("monto3-revalidate-{}.txt", ::rand::random::<u64>()));
+    File::create(&path).unwrap().write_all(b"foo").unwrap();
+    let pi = ProductIdentifier {
+        name: ProductName::Source,
+        language: Language::Text,
+        path: path.display().to_string(),
+    };
+    let si: Identifier = "edu.umn.cs.melt.monto_test".parse().unwrap();
+    cache.borrow_mut().add(si.clone(), Product {
+        name: pi.name.clone(),
+        language: pi.language.clone(),
+        path: pi.path.clone(),
+        value: Value::String("foo".to_owned()),
+    });
+
+    File::create(&path).unwrap().write_all(b"foo").unwrap();
+    core.run(Cache::revalidate(&cache, path.clone())).unwrap();
+    assert!(cache.borrow().get(&si, pi.clone()).is_some());
+
+    File::create(&path).unwrap().write_all(b"bar").unwrap();
+    core.run(Cache::revalidate_all(&cache)).unwrap();
+    assert!(cache.borrow().get(&si, pi).is_none());
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn poll_test() {
+    use std::env::temp_dir;
+    use std::io::Write;
+    use std::time::Instant;
+
+    use tokio_core::reactor::{Core, Timeout};
+
+    use monto3_common::messages::Language;
+
+    let mut core = Core::new().unwrap();
+    let config = BrokerConfig {
+        poll: vec!["**/*.txt".to_owned()],
+        poll_interval: 50,
+        ..BrokerConfig::default()
+    };
+    let cache = Cache::new(&config, CpuPool::new(1), &core.handle()).unwrap();
+    assert!(cache.borrow().poll_watcher.is_some());
+
+    let path = temp_dir().join(format!("monto3-poll-{}.txt", ::rand::random::<u64>()));
+    assert!(cache.borrow().poll.is_match(&path));
+    assert!(!cache.borrow().poll.is_match(path.with_extension("json")));
+    File::create(&path).unwrap().write_all(b"foo").unwrap();
+    let pi = ProductIdentifier {
+        name: ProductName::Source,
+        language: Language::Text,
+        path: path.display().to_string(),
+    };
+    let si: Identifier = "edu.umn.cs.melt.monto_test".parse().unwrap();
+    cache.borrow_mut().add(si.clone(), Product {
+        name: pi.name.clone(),
+        language: pi.language.clone(),
+        path: pi.path.clone(),
+        value: Value::String("foo".to_owned()),
+    });
+
+    // The poll watcher only compares modification times to the second.
+    thread::sleep(Duration::from_millis(1100));
+    File::create(&path).unwrap().write_all(b"bar").unwrap();
+    let start = Instant::now();
+    while cache.borrow().get(&si, pi.clone()).is_some() {
+        assert!(start.elapsed() < Duration::from_secs(5), "edit was never polled");
+        let timeout = Timeout::new(Duration::from_millis(50), &core.handle()).unwrap();
+        core.run(timeout).unwrap();
+    }
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn service_key_test() {
+    use tokio_core::reactor::Core;
+
+    use monto3_common::messages::Language;
+
+    let core = Core::new().unwrap();
+    let cache = Cache::new(&BrokerConfig::default(), CpuPool::new(1), &core.handle()).unwrap();
+    let a: Identifier = "edu.umn.cs.melt.monto_test.a".parse().unwrap();
+    let b: Identifier = "edu.umn.cs.melt.monto_test.b".parse().unwrap();
+
+    let source = ProductIdentifier {
+        name: ProductName::Source,
+        language: Language::Text,
+        path: "/monto3-service-key.txt".to_owned(),
+    };
+    let errors = ProductIdentifier {
+        name: ProductName::Errors,
+        ..source.clone()
+    };
+    for pi in &[&source, &errors] {
+        cache.borrow_mut().add(a.clone(), Product {
+            name: pi.name.clone(),
+            language: pi.language.clone(),
+            path: pi.path.clone(),
+            value: Value::Null,
+        });
+    }
+
+    // Sources are shared, but other products belong to their Service.
+    assert!(cache.borrow().get(&b, source).is_some());
+    assert!(cache.borrow().get(&a, errors.clone()).is_some());
+    assert!(cache.borrow().get(&b, errors).is_none());
+}
@@ -0,0 +1,78 @@
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::path::PathBuf;
+
+use ignore::WalkBuilder;
+
+use monto3_common::paths::file_path;
+use monto3_common::products::{Directory, DirectoryEntry, DirectoryEntryType};
+
+/// Lists the directory at the given path.
+///
+/// Files ignored by `.gitignore` or `.ignore` files are left out, as are
+/// hidden files unless `hidden` is true.
+pub fn list_directory(path: &str, hidden: bool) -> IoResult<Directory> {
+    let dir = directory_path(path)?;
+
+    let mut entries = Vec::new();
+    for entry in WalkBuilder::new(&dir)
+        .hidden(!hidden)
+        .max_depth(Some(1))
+        .build()
+    {
+        match entry {
+            Ok(ref entry) if entry.depth() == 0 => {}
+            Ok(entry) => entries.push(DirectoryEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                absolute_path: entry.path().to_owned(),
+                file_type: entry
+                    .file_type()
+                    .map(DirectoryEntryType::from)
+                    .unwrap_or(DirectoryEntryType::Other),
+            }),
+            Err(err) => warn!("While listing {}: {}", dir.display(), err),
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Directory {
+        path: path.to_string(),
+        entries,
+    })
+}
+
+/// Finds the files under the directory at the given path, recursively, in
+/// order of their paths.
+///
+/// Files are left out as they are by `list_directory`.
+pub fn walk_directory(path: &str, hidden: bool) -> IoResult<Vec<PathBuf>> {
+    let dir = directory_path(path)?;
+
+    let mut files = Vec::new();
+    for entry in WalkBuilder::new(&dir).hidden(!hidden).build() {
+        match entry {
+            Ok(entry) => if entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                files.push(entry.into_path());
+            },
+            Err(err) => warn!("While walking {}: {}", dir.display(), err),
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Returns the canonical path of the directory a path refers to, or an error
+/// if it doesn't refer to one.
+fn directory_path(path: &str) -> IoResult<PathBuf> {
+    let dir = match file_path(path) {
+        Some(dir) => dir.canonicalize()?,
+        None => {
+            let msg = format!("{} is not a file path", path);
+            return Err(IoError::new(ErrorKind::Other, msg));
+        }
+    };
+    if !dir.is_dir() {
+        let msg = format!("{} is not a directory", dir.display());
+        return Err(IoError::new(ErrorKind::Other, msg));
+    }
+    Ok(dir)
+}
@@ -0,0 +1,133 @@
+//! Merging the errors from every Service that provides them for a source.
+
+use futures::Future;
+use futures::future::{err, join_all};
+use serde_json::from_value;
+
+use monto3_client::messages::BrokerGetError;
+use monto3_common::messages::{Identifier, Product, ProductDescriptor, ProductIdentifier,
+                              ProductName};
+use monto3_common::products::{Error, Errors};
+
+use Broker;
+use client::Client;
+
+impl Broker {
+    /// Returns the descriptors of the `errors` products the Broker merges
+    /// from other Services, one for each language any Service provides them
+    /// in.
+    pub fn merged_products(&self) -> Vec<ProductDescriptor> {
+        self.services
+            .iter()
+            .flat_map(|s| s.negotiation.products.iter())
+            .filter(|pd| pd.name == ProductName::Errors)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Client {
+    /// Resolves the errors for a source from every Service that provides
+    /// them, and from the Broker's translations, then merges them. Each error
+    /// is tagged with the Services it came from, and duplicates are removed.
+    ///
+    /// The errors from each Service are cached like any other product. The
+    /// merged and translated errors would both be cached as the Broker's own,
+    /// so neither of them is.
+    pub(super) fn resolve_errors(
+        self,
+        pi: ProductIdentifier,
+    ) -> Box<Future<Item = Product, Error = BrokerGetError>> {
+        let (id, services) = {
+            let broker = self.broker.borrow();
+            let pd = ProductDescriptor {
+                name: pi.name.clone(),
+                language: pi.language.clone(),
+            };
+            let services = broker
+                .services
+                .iter()
+                .filter(|s| s.negotiation.products.contains(&pd))
+                .map(|s| s.negotiation.service.id.clone())
+                .collect::<Vec<_>>();
+            (broker.version().id, services)
+        };
+
+        let mut futures = services
+            .into_iter()
+            .map(|si| {
+                let request = self.clone().resolve(si.clone(), pi.clone(), Vec::new());
+                Box::new(request.then(move |r| Ok((si, r))))
+                    as Box<Future<Item = _, Error = BrokerGetError>>
+            })
+            .collect::<Vec<_>>();
+        if let Some(translated) = self.resolve_translated(pi.clone(), false) {
+            futures.push(Box::new(translated.then(move |r| Ok((id, r)))));
+        }
+        if futures.is_empty() {
+            return Box::new(err(BrokerGetError::NoSuchProduct));
+        }
+        Box::new(join_all(futures).and_then(move |results| merge_errors(pi, results)))
+    }
+}
+
+/// Merges the errors from each Service. Services that fail are skipped,
+/// unless they all do, or the source changed while the errors were being
+/// computed.
+fn merge_errors(
+    pi: ProductIdentifier,
+    results: Vec<(Identifier, Result<Product, BrokerGetError>)>,
+) -> Result<Product, BrokerGetError> {
+    let mut errors = Vec::new();
+    let mut failure = None;
+    let mut succeeded = false;
+    for (si, result) in results {
+        let parsed = result.and_then(|p| {
+            from_value::<Vec<Error>>(p.value).map_err(|e| BrokerGetError::ServiceError {
+                service: si.clone(),
+                error: e.to_string(),
+            })
+        });
+        match parsed {
+            Ok(es) => {
+                succeeded = true;
+                errors.extend(es.into_iter().map(|mut e| {
+                    e.services = vec![si.clone()];
+                    e
+                }));
+            }
+            Err(BrokerGetError::SourceChanged) => return Err(BrokerGetError::SourceChanged),
+            Err(e) => {
+                warn!("Couldn't get errors from {}: {}", si, e);
+                failure = failure.or(Some(e));
+            }
+        }
+    }
+    if let (false, Some(e)) = (succeeded, failure) {
+        return Err(e);
+    }
+
+    // An error reported by several Services is kept once, tagged with all
+    // of them.
+    errors.sort_by(|a, b| {
+        (a.start_byte, a.end_byte, a.severity, &a.message)
+            .cmp(&(b.start_byte, b.end_byte, b.severity, &b.message))
+    });
+    errors.dedup_by(|a, b| {
+        let same = (a.start_byte, a.end_byte, a.severity, &a.message)
+            == (b.start_byte, b.end_byte, b.severity, &b.message);
+        if same {
+            b.services.append(&mut a.services);
+        }
+        same
+    });
+    for error in &mut errors {
+        error.services.sort();
+        error.services.dedup();
+    }
+    Ok(Product::from(Errors {
+        errors,
+        language: pi.language,
+        path: pi.path,
+    }))
+}
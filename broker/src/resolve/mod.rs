@@ -1,87 +1,149 @@
 //! Dependency resolution and product caching for the broker.
 
 mod cache;
+mod directory;
+mod errors;
+mod precompute;
+mod translate;
 mod watcher;
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::prelude::*;
 
-use futures::Future;
-use futures::future::{err, ok};
+use futures::{Async, Future};
+use futures::future::{err, ok, Either};
 use serde_json::Value;
 
 use monto3_client::messages::BrokerGetError;
-use monto3_common::messages::{Identifier, Product, ProductDescriptor, ProductIdentifier,
-                              ProductName};
+use monto3_common::messages::{Identifier, Language, Product, ProductDescriptor,
+                              ProductIdentifier, ProductName};
+use monto3_common::paths::file_path;
 use monto3_service::messages::{ServiceError, ServiceErrors, ServiceNotice};
 
 use Broker;
 use client::Client;
 pub use resolve::cache::Cache;
+use resolve::directory::list_directory;
+pub(crate) use resolve::directory::walk_directory;
+pub use resolve::precompute::Precompute;
 use service::{RequestError, RequestErrorKind};
 
+/// The versions of the sources a product is being computed from, by path, as
+/// of when each was first used.
+type SourceVersions = BTreeMap<String, u64>;
+
 impl Client {
     /// Fully resolves a product request, including doing dependency resolution.
     pub fn resolve(
         self,
         si: Identifier,
         pi: ProductIdentifier,
-        mut ps: Vec<Product>,
+        ps: Vec<Product>,
     ) -> Box<Future<Item = Product, Error = BrokerGetError>> {
         let self2 = self.clone();
-        let broker = self2.0.borrow();
+        let broker = self2.broker.borrow();
         info!("getting {:?} from {}", pi, si);
 
-        if let Some(gp) = broker.from_cache(pi.clone()) {
+        if si == broker.version().id && pi.name == ProductName::Errors {
+            self.resolve_errors(pi)
+        } else if let Some(gp) = broker.from_cache(&si, pi.clone()) {
             Box::new(ok(gp))
+        } else if si == broker.version().id {
+            self.resolve_native(pi)
         } else {
-            if let Some(service) = broker.find_service(&si) {
-                Box::new(service.request(pi.clone(), &ps).then(move |r| match r {
-                    Ok(sp) => Box::new(ok(sp.product)),
-                    Err(RequestError(e, _)) => {
-                        error!("{}", e);
-                        match e {
-                            RequestErrorKind::Hyper(e) => {
-                                Box::new(err(BrokerGetError::ServiceConnectError {
-                                    service: si,
-                                    error: e.to_string(),
-                                }))
-                            }
-                            RequestErrorKind::ServiceErrors(ServiceErrors { errors, notices }) => {
-                                for ServiceNotice::UnusedDependency(pi) in notices {
-                                    let idx = ps.iter()
-                                        .cloned()
-                                        .map(ProductIdentifier::from)
-                                        .position(|pi2| pi2 == pi);
-                                    if let Some(idx) = idx {
-                                        ps.swap_remove(idx);
-                                    } else {
-                                        warn!("Couldn't find {:?} in {:?}", pi, ps);
-                                    }
-                                }
-                                self.resolve_next(si, pi, ps, errors)
-                            }
-                            _ => Box::new(err(BrokerGetError::ServiceError {
+            let mut versions = SourceVersions::new();
+            broker.record_version(&mut versions, &pi.path);
+            for p in &ps {
+                broker.record_version(&mut versions, &p.path);
+            }
+            self.resolve_service(si, pi, ps, versions)
+        }
+    }
+
+    /// Resolves a product from a Service, including doing dependency
+    /// resolution, without checking the cache first.
+    ///
+    /// If any source the product is computed from changes in the meantime,
+    /// the product is never cached or returned. A change to the source at
+    /// the product's own path also abandons the computation right away, and
+    /// the Service is told it was cancelled.
+    fn resolve_service(
+        self,
+        si: Identifier,
+        pi: ProductIdentifier,
+        mut ps: Vec<Product>,
+        versions: SourceVersions,
+    ) -> Box<Future<Item = Product, Error = BrokerGetError>> {
+        let self2 = self.clone();
+        let broker = self2.broker.borrow();
+        if let Some(service) = broker.find_service(&si) {
+            let cache = broker.cache.clone();
+            let changed = cache.borrow_mut().changed(&pi.path);
+            let request = service.request(pi.clone(), &ps).select2(changed);
+            Box::new(request.then(move |r| match r {
+                Ok(Either::A((sp, mut changed))) => {
+                    let unchanged = changed.poll() == Ok(Async::NotReady)
+                        && self.broker.borrow().sources_unchanged(&versions);
+                    if unchanged {
+                        cache.borrow_mut().add(si, sp.product.clone());
+                        Box::new(ok(sp.product))
+                    } else {
+                        info!("A source changed while computing {:?}", pi);
+                        Box::new(err(BrokerGetError::SourceChanged))
+                    }
+                }
+                Ok(Either::B(_)) | Err(Either::B(_)) => {
+                    info!("The source changed while computing {:?}", pi);
+                    if let Some(service) = self.broker.borrow().find_service(&si) {
+                        service.cancel(&pi);
+                    }
+                    Box::new(err(BrokerGetError::SourceChanged))
+                }
+                Err(Either::A((RequestError(e, _), _))) => {
+                    error!("{}", e);
+                    match e {
+                        RequestErrorKind::Hyper(e) => {
+                            Box::new(err(BrokerGetError::ServiceConnectError {
                                 service: si,
                                 error: e.to_string(),
-                            })),
+                            }))
                         }
+                        RequestErrorKind::ServiceErrors(ServiceErrors { errors, notices }) => {
+                            for ServiceNotice::UnusedDependency(pi) in notices {
+                                let idx = ps.iter()
+                                    .cloned()
+                                    .map(ProductIdentifier::from)
+                                    .position(|pi2| pi2 == pi);
+                                if let Some(idx) = idx {
+                                    ps.swap_remove(idx);
+                                } else {
+                                    warn!("Couldn't find {:?} in {:?}", pi, ps);
+                                }
+                            }
+                            self.resolve_next(si, pi, ps, errors, versions)
+                        }
+                        _ => Box::new(err(BrokerGetError::ServiceError {
+                            service: si,
+                            error: e.to_string(),
+                        })),
                     }
-                }))
-            } else {
-                Box::new(err(BrokerGetError::NoSuchService))
-            }
+                }
+            }))
+        } else {
+            Box::new(err(BrokerGetError::NoSuchService))
         }
     }
 
-    /// Resolves from any service.
+    /// Resolves from any service. Products sent by a Client or produced by
+    /// the Broker itself are preferred.
     fn resolve_dep(
         self,
         pi: ProductIdentifier,
     ) -> Box<Future<Item = Product, Error = BrokerGetError>> {
         let service = {
-            let broker = self.0.borrow();
-            if let Some(gp) = broker.from_cache(pi.clone()) {
+            let broker = self.broker.borrow();
+            if let Some(gp) = broker.from_cache(&broker.version().id, pi.clone()) {
                 return Box::new(ok(gp));
             } else {
                 let pd = ProductDescriptor {
@@ -98,8 +160,14 @@ impl Client {
         if let Some(si) = service {
             self.resolve(si, pi, vec![])
         } else if pi.name == ProductName::Source {
+            // A source that isn't a file and hasn't been sent by a Client
+            // can't be found anywhere else.
+            let file = match file_path(&pi.path) {
+                Some(file) => file,
+                None => return Box::new(err(BrokerGetError::Unresolvable(pi))),
+            };
             let mut s = String::new();
-            let e = File::open(&pi.path)
+            let e = File::open(&file)
                 .and_then(|mut f| f.read_to_string(&mut s))
                 .err();
             if let Some(e) = e {
@@ -112,15 +180,44 @@ impl Client {
                     path: pi.path,
                     value: Value::String(s),
                 };
-                let broker = self.0.borrow();
-                broker.cache.borrow_mut().add(p.clone());
+                let broker = self.broker.borrow();
+                broker.cache.borrow_mut().add(broker.version().id, p.clone());
                 Box::new(ok(p))
             }
+        } else if pi.name == ProductName::Directory
+            || self.broker.borrow().find_translation(&pi).is_some()
+        {
+            self.resolve_native(pi)
         } else {
             Box::new(err(BrokerGetError::Unresolvable(pi)))
         }
     }
 
+    /// Resolves a product the Broker produces itself, such as a directory
+    /// listing or a translated product.
+    fn resolve_native(
+        self,
+        pi: ProductIdentifier,
+    ) -> Box<Future<Item = Product, Error = BrokerGetError>> {
+        if pi.name != ProductName::Directory || pi.language != Language::None {
+            return self.resolve_translated(pi, true)
+                .unwrap_or_else(|| Box::new(err(BrokerGetError::NoSuchProduct)));
+        }
+
+        let broker = self.broker.borrow();
+        match list_directory(&pi.path, broker.config.broker.list_hidden_files) {
+            Ok(dir) => {
+                let p = Product::from(dir);
+                broker.cache.borrow_mut().add(broker.version().id, p.clone());
+                Box::new(ok(p))
+            }
+            Err(e) => {
+                error!("{}", e);
+                Box::new(err(BrokerGetError::Unresolvable(pi)))
+            }
+        }
+    }
+
     /// Handles the error case of resolve.
     fn resolve_next(
         self,
@@ -128,13 +225,18 @@ impl Client {
         pi: ProductIdentifier,
         mut ps: Vec<Product>,
         mut es: Vec<ServiceError>,
+        mut versions: SourceVersions,
     ) -> Box<Future<Item = Product, Error = BrokerGetError>> {
         if let Some(se) = es.pop() {
             match se {
                 ServiceError::UnmetDependency(pi2) => {
-                    Box::new(self.clone().resolve_dep(pi2).and_then(|p| {
+                    // A source that isn't cached yet is read while resolving
+                    // the dependency, so its version is only known afterwards.
+                    self.broker.borrow().record_version(&mut versions, &pi2.path);
+                    Box::new(self.clone().resolve_dep(pi2).and_then(move |p| {
+                        self.broker.borrow().record_version(&mut versions, &p.path);
                         ps.push(p);
-                        self.resolve_next(si, pi, ps, es)
+                        self.resolve_next(si, pi, ps, es, versions)
                     }))
                 }
                 ServiceError::Other(s) => Box::new(err(BrokerGetError::ServiceError {
@@ -143,15 +245,34 @@ impl Client {
                 })),
             }
         } else {
-            self.resolve(si, pi, ps)
+            self.resolve_service(si, pi, ps, versions)
         }
     }
 }
 
 impl Broker {
-    /// Tries to retrieve a product from the cache.
-    fn from_cache(&self, pi: ProductIdentifier) -> Option<Product> {
+    /// Tries to retrieve a product produced by the given Service from the
+    /// cache.
+    fn from_cache(&self, si: &Identifier, pi: ProductIdentifier) -> Option<Product> {
+        let cache = self.cache.borrow();
+        cache.get(si, pi)
+    }
+
+    /// Records the current version of the source at a path, unless one was
+    /// recorded already, or the source isn't cached. A source that was
+    /// evicted keeps its old version until it is read again, so that version
+    /// isn't recorded.
+    fn record_version(&self, versions: &mut SourceVersions, path: &str) {
+        if let Some(version) = self.cache.borrow().cached_source_version(path) {
+            versions.entry(path.to_owned()).or_insert(version);
+        }
+    }
+
+    /// Returns whether the sources are all still at the recorded versions.
+    fn sources_unchanged(&self, versions: &SourceVersions) -> bool {
         let cache = self.cache.borrow();
-        cache.get(pi)
+        versions
+            .iter()
+            .all(|(path, &version)| cache.source_version(path) == Some(version))
     }
 }
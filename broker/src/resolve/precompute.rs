@@ -0,0 +1,68 @@
+//! Computing products in the background when sources are sent to the Broker.
+
+use futures::Future;
+use futures::future::lazy;
+use globset::{Error as GlobError, Glob, GlobMatcher};
+
+use monto3_common::messages::{Language, ProductIdentifier, ProductName};
+
+use client::Client;
+use config::PrecomputeConfig;
+
+/// A rule for which products to compute when a source is sent to the Broker.
+#[derive(Debug)]
+pub struct Precompute {
+    glob: Option<GlobMatcher>,
+    language: Option<Language>,
+    products: Vec<ProductName>,
+}
+
+impl Precompute {
+    /// Compiles a rule from its configuration.
+    pub fn new(config: &PrecomputeConfig) -> Result<Precompute, GlobError> {
+        let glob = match config.glob {
+            Some(ref glob) => Some(Glob::new(glob)?.compile_matcher()),
+            None => None,
+        };
+        Ok(Precompute {
+            glob,
+            language: config.language.clone(),
+            products: config.products.clone(),
+        })
+    }
+
+    /// Returns whether a source at the given path and in the given language
+    /// is matched by the rule.
+    fn matches(&self, path: &str, language: &Language) -> bool {
+        self.glob.as_ref().map(|glob| glob.is_match(path)).unwrap_or(true)
+            && self.language.as_ref().map(|l| l == language).unwrap_or(true)
+    }
+}
+
+impl Client {
+    /// Starts computing the products configured to be precomputed for a
+    /// source. They are resolved like dependencies, and cached once they
+    /// are computed; errors are only logged.
+    pub fn precompute(&self, path: &str, language: &Language) {
+        let broker = self.broker.borrow();
+        for rule in broker.precompute.iter().filter(|r| r.matches(path, language)) {
+            for name in &rule.products {
+                let pi = ProductIdentifier {
+                    name: name.clone(),
+                    language: language.clone(),
+                    path: path.to_owned(),
+                };
+                let client = self.clone();
+                broker.handle.spawn(lazy(move || {
+                    info!("Precomputing {:?}", pi);
+                    client.resolve_dep(pi.clone()).then(move |r| {
+                        if let Err(e) = r {
+                            warn!("Couldn't precompute {:?}: {:?}", pi, e);
+                        }
+                        Ok(())
+                    })
+                }));
+            }
+        }
+    }
+}
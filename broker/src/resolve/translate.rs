@@ -0,0 +1,202 @@
+//! Producing products by translating the products of Services.
+
+use futures::Future;
+use futures::future::ok;
+use serde_json::{Map, Value};
+
+use monto3_client::messages::BrokerGetError;
+use monto3_common::messages::{Product, ProductDescriptor, ProductIdentifier, ProductName};
+
+use Broker;
+use client::Client;
+use config::{PositionConfig, TranslateConfig};
+
+impl Broker {
+    /// Returns the descriptors of the products produced by translation.
+    pub fn translated_products(&self) -> Vec<ProductDescriptor> {
+        self.config
+            .translate
+            .iter()
+            .map(|t| ProductDescriptor {
+                name: t.to.clone(),
+                language: t.language.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns the translation that produces the given product, if one
+    /// exists.
+    pub(super) fn find_translation(&self, pi: &ProductIdentifier) -> Option<&TranslateConfig> {
+        self.config
+            .translate
+            .iter()
+            .find(|t| t.to == pi.name && t.language == pi.language)
+    }
+}
+
+impl Client {
+    /// Resolves a product produced by translation, if the Broker has a
+    /// translation for it. The product being translated is resolved like a
+    /// dependency. If `cache` is false, the translated product isn't added to
+    /// the cache.
+    pub(super) fn resolve_translated(
+        self,
+        pi: ProductIdentifier,
+        cache: bool,
+    ) -> Option<Box<Future<Item = Product, Error = BrokerGetError>>> {
+        let translation = match self.broker.borrow().find_translation(&pi) {
+            Some(translation) => translation.clone(),
+            None => return None,
+        };
+        let from = ProductIdentifier {
+            name: translation.from.clone(),
+            language: pi.language.clone(),
+            path: pi.path.clone(),
+        };
+        let source: Box<Future<Item = _, Error = _>> = if translation.positions.is_empty() {
+            Box::new(ok(None))
+        } else {
+            let source = ProductIdentifier {
+                name: ProductName::Source,
+                language: pi.language.clone(),
+                path: pi.path.clone(),
+            };
+            Box::new(self.clone().resolve_dep(source).map(Some))
+        };
+
+        let broker = self.broker.clone();
+        let future = self.resolve_dep(from)
+            .join(source)
+            .and_then(move |(product, source)| {
+                let source = source.as_ref().and_then(|s| s.value.as_str());
+                let broker = broker.borrow();
+                let value = translate(&translation, product.value, source).map_err(|error| {
+                    BrokerGetError::ServiceError {
+                        service: broker.version().id,
+                        error,
+                    }
+                })?;
+                let p = Product {
+                    name: pi.name,
+                    language: pi.language,
+                    path: pi.path,
+                    value,
+                };
+                if cache {
+                    broker.cache.borrow_mut().add(broker.version().id, p.clone());
+                }
+                Ok(p)
+            });
+        Some(Box::new(future))
+    }
+}
+
+/// Translates the value of a product. The source is only needed if the
+/// translation has positions.
+fn translate(
+    translation: &TranslateConfig,
+    value: Value,
+    source: Option<&str>,
+) -> Result<Value, String> {
+    if translation.fields.is_empty() && translation.positions.is_empty() {
+        return Ok(value);
+    }
+    match value {
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| translate_object(translation, value, source))
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        value => translate_object(translation, value, source),
+    }
+}
+
+fn translate_object(
+    translation: &TranslateConfig,
+    value: Value,
+    source: Option<&str>,
+) -> Result<Value, String> {
+    let mut object = match value {
+        Value::Object(object) => object,
+        value => return Err(format!("Expected an object, got {}", value)),
+    };
+    let mut translated = Map::new();
+    for (to, from) in &translation.fields {
+        match object.remove(from) {
+            Some(value) => translated.insert(to.clone(), value),
+            None => return Err(format!("Missing field {}", from)),
+        };
+    }
+    for (to, position) in &translation.positions {
+        let source = source.ok_or("No source to translate positions in")?;
+        let byte = translate_position(&object, position, source, translation.one_based)?;
+        translated.insert(to.clone(), byte.into());
+    }
+    Ok(Value::Object(translated))
+}
+
+/// Finds the byte offset of the line and column in the given fields.
+fn translate_position(
+    object: &Map<String, Value>,
+    position: &PositionConfig,
+    source: &str,
+    one_based: bool,
+) -> Result<usize, String> {
+    let field = |name: &str| {
+        let n = object
+            .get(name)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| format!("Missing or invalid field {}", name))?;
+        if one_based {
+            n.checked_sub(1)
+                .ok_or_else(|| format!("Field {} is 0, but positions are one-based", name))
+        } else {
+            Ok(n)
+        }
+    };
+    let (line, column) = (field(&position.line)?, field(&position.column)?);
+    line_column_to_byte(source, line as usize, column as usize)
+        .ok_or_else(|| format!("No position {}:{} in the source", line, column))
+}
+
+/// Converts a zero-based line and column to a byte offset. The column may be
+/// at the end of the line.
+fn line_column_to_byte(source: &str, line: usize, column: usize) -> Option<usize> {
+    let start = if line == 0 {
+        0
+    } else {
+        source.match_indices('\n').nth(line - 1)?.0 + 1
+    };
+    let line = source[start..].split('\n').next().unwrap_or("");
+    line.char_indices()
+        .map(|(i, _)| i)
+        .chain(Some(line.len()))
+        .nth(column)
+        .map(|i| start + i)
+}
+
+#[test]
+fn translate_test() {
+    use serde_json::from_str;
+    use toml;
+
+    let translation: TranslateConfig = toml::from_str(
+        r#"
+        from = "edu.umn.cs.melt.ablec.errors"
+        to = "errors"
+        language = "c"
+        one_based = true
+        fields = { message = "msg" }
+        positions = { start_byte = { line = "line", column = "col" } }
+    "#,
+    ).unwrap();
+    let value = from_str(r#"[{ "msg": "bad", "line": 2, "col": 3, "extra": null }]"#).unwrap();
+    let translated = translate(&translation, value, Some("ab\nc\u{e9}d\n")).unwrap();
+    assert_eq!(
+        translated,
+        from_str::<Value>(r#"[{ "message": "bad", "start_byte": 6 }]"#).unwrap()
+    );
+
+    let value = from_str(r#"{ "msg": "bad", "line": 3, "col": 2 }"#).unwrap();
+    assert!(translate(&translation, value, Some("ab\nc\u{e9}d\n")).is_err());
+}
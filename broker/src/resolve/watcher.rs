@@ -1,21 +1,32 @@
 use std::cell::RefCell;
-use std::ops::DerefMut;
-use std::path::PathBuf;
 use std::rc::Rc;
 
-use futures::{Async, Future};
+use futures::{Async, Future, Stream};
+use futures::sync::mpsc::UnboundedReceiver;
 use notify::DebouncedEvent;
+use tokio_core::reactor::Handle;
 
 use super::cache::Cache;
 
-/// A future for filesystem events. Will never resolve.
+/// A future for filesystem events. Only resolves if the filesystem watcher
+/// dies.
 pub struct Watcher {
     cache: Rc<RefCell<Cache>>,
+    events: UnboundedReceiver<DebouncedEvent>,
+    handle: Handle,
 }
 
 impl Watcher {
-    pub fn new(cache: Rc<RefCell<Cache>>) -> Watcher {
-        Watcher { cache }
+    pub fn new(
+        cache: Rc<RefCell<Cache>>,
+        events: UnboundedReceiver<DebouncedEvent>,
+        handle: Handle,
+    ) -> Watcher {
+        Watcher {
+            cache,
+            events,
+            handle,
+        }
     }
 }
 
@@ -24,35 +35,44 @@ impl Future for Watcher {
     type Error = ();
 
     fn poll(&mut self) -> Result<Async<()>, ()> {
-        let mut cache = self.cache.borrow_mut();
-        let cache = cache.deref_mut();
-        while let Some(ev) = cache.event() {
-            match ev {
-                DebouncedEvent::NoticeWrite(path) => recursive_evict(cache, path),
-                DebouncedEvent::NoticeRemove(path) => recursive_evict(cache, path),
-                DebouncedEvent::Create(path) => recursive_evict(cache, path),
-                DebouncedEvent::Write(path) => recursive_evict(cache, path),
-                DebouncedEvent::Chmod(path) => recursive_evict(cache, path),
-                DebouncedEvent::Remove(path) => recursive_evict(cache, path),
-                DebouncedEvent::Rename(path, _) => recursive_evict(cache, path),
-                DebouncedEvent::Rescan => {}
+        loop {
+            let ev = match self.events.poll()? {
+                Async::Ready(Some(ev)) => ev,
+                Async::Ready(None) => {
+                    error!(
+                        "The filesystem watcher died; https://twitter.com/rob_pike/status/447202124753952768"
+                    );
+                    return Ok(Async::Ready(()));
+                }
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            let cache = &self.cache;
+            let revalidate = match ev {
+                DebouncedEvent::NoticeWrite(path) => Cache::revalidate(cache, path),
+                DebouncedEvent::NoticeRemove(path) => Cache::revalidate(cache, path),
+                DebouncedEvent::Create(path) => Cache::revalidate(cache, path),
+                DebouncedEvent::Write(path) => Cache::revalidate(cache, path),
+                DebouncedEvent::Chmod(path) => Cache::revalidate(cache, path),
+                DebouncedEvent::Remove(path) => Cache::revalidate(cache, path),
+                DebouncedEvent::Rename(from, to) => Box::new(
+                    Cache::revalidate(cache, from)
+                        .join(Cache::revalidate(cache, to))
+                        .map(|_| ()),
+                ),
+                DebouncedEvent::Rescan => {
+                    info!("The filesystem watcher may have missed events; revalidating the cache");
+                    Cache::revalidate_all(cache)
+                }
                 DebouncedEvent::Error(err, path) => {
                     error!("{}", err);
-                    if let Some(path) = path {
-                        recursive_evict(cache, path)
+                    match path {
+                        Some(path) => Cache::revalidate(cache, path),
+                        None => Cache::revalidate_all(cache),
                     }
                 }
-            }
+            };
+            self.handle.spawn(revalidate);
         }
-        Ok(Async::NotReady)
-    }
-}
-
-fn recursive_evict(cache: &mut Cache, mut path: PathBuf) {
-    info!("Evicting path {} from cache", path.display());
-    cache.evict_by_path(path.clone());
-    while path.pop() {
-        info!("Evicting path {} from cache", path.display());
-        cache.evict_by_path(path.clone());
     }
 }
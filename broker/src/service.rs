@@ -1,31 +1,37 @@
 //! The Service Protocol side of the Broker.
 
-use std::cmp::min;
+use std::cell::RefCell;
 use std::collections::BTreeSet;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Error as IoError;
+use std::rc::Rc;
+use std::time::Duration;
 
 use futures::{Future, Stream};
 use futures::future::{err, ok, result};
-use hyper::{Body, Chunk, Client, Error as HyperError, Method, Request, StatusCode};
+use hyper::{Body, Client, Error as HyperError, Method, Request, StatusCode};
 use hyper::client::HttpConnector;
 use hyper::error::UriError;
-use hyper::header::ContentType;
+use hyper::header::{ContentEncoding, ContentType};
 use itertools::Itertools;
 use serde_json;
 use serde_json::Error as JsonError;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 
+use monto3_common::encoding::{accept_encoding, accepts_gzip, decompress, gzip_if_large};
+use monto3_common::format::Format;
 use monto3_common::messages::{Product, ProductDescriptor, ProductIdentifier, ProtocolVersion};
+use monto3_service::{ProvideError, Service as InProcessService};
 use monto3_service::messages::{BrokerRequest, ServiceBrokerNegotiation, ServiceErrors,
                                ServiceExtension, ServiceNegotiation, ServiceProduct};
 
 use config::{Config, ServiceConfig};
+use extensions::Extensions;
+use record::{Record, Recorder, ServiceExchange, ServiceReplay, ServiceResponse};
 
 /// A connection from the Broker to a Service.
 #[derive(Debug)]
 pub struct Service {
-    /// The configuration for connecting to the Service.
-    pub config: ServiceConfig,
-
     /// The Service Protocol Extensions enabled.
     pub extensions: BTreeSet<ServiceExtension>,
 
@@ -35,14 +41,92 @@ pub struct Service {
     /// The Service Protocol version being used to communicate to the Service.
     pub protocol: ProtocolVersion,
 
-    client: Client<HttpConnector, Body>,
+    handlers: Extensions,
+    recorder: Option<Recorder>,
+    transport: Transport,
+}
+
+/// How the Broker communicates with a Service.
+enum Transport {
+    /// The Service is reached over HTTP. If `compress` is set, the Service
+    /// accepts compressed request bodies. Requests are sent in `format`, and
+    /// fail if the Service doesn't respond within `timeout`.
+    Http {
+        client: Client<HttpConnector, Body>,
+        config: ServiceConfig,
+        compress: bool,
+        format: Format,
+        timeout: Duration,
+    },
+
+    /// The Service runs in the Broker's process.
+    InProcess(Rc<RefCell<InProcessService>>),
+
+    /// The Service is stood in for by the responses recorded in a session.
+    Replay(ServiceReplay),
+}
+
+impl Debug for Transport {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Transport::Http { ref config, .. } => fmt.debug_tuple("Http").field(config).finish(),
+            Transport::InProcess(_) => fmt.debug_tuple("InProcess").finish(),
+            Transport::Replay(_) => fmt.debug_tuple("Replay").finish(),
+        }
+    }
 }
 
 impl Service {
+    /// Wraps a Service to be run in the Broker's process. Products are
+    /// requested from it directly, instead of over HTTP.
+    ///
+    /// No Service Protocol Extensions are enabled for an in-process Service.
+    pub fn in_process(service: InProcessService) -> Service {
+        let negotiation = service.negotiation();
+        Service {
+            extensions: BTreeSet::new(),
+            handlers: Extensions::default(),
+            protocol: negotiation.monto,
+            negotiation,
+            recorder: None,
+            transport: Transport::InProcess(Rc::new(RefCell::new(service))),
+        }
+    }
+
+    /// Creates a stand-in for a Service from a recorded session, which
+    /// responds to requests with the responses recorded for them.
+    pub fn replay(negotiation: ServiceNegotiation, records: &[Record]) -> Service {
+        let replay = ServiceReplay::new(&negotiation.service.id, records);
+        Service {
+            extensions: BTreeSet::new(),
+            handlers: Extensions::default(),
+            protocol: negotiation.monto,
+            negotiation,
+            recorder: None,
+            transport: Transport::Replay(replay),
+        }
+    }
+
+    /// Records the requests sent to the Service and its responses.
+    pub fn record_to(&mut self, recorder: Recorder) {
+        recorder.record(&Record::Service(self.negotiation.clone()));
+        self.recorder = Some(recorder);
+    }
+
+    /// Returns the configuration used to connect to the Service, or `None` if
+    /// it runs in-process.
+    pub fn config(&self) -> Option<&ServiceConfig> {
+        match self.transport {
+            Transport::Http { ref config, .. } => Some(config),
+            Transport::InProcess(_) | Transport::Replay(_) => None,
+        }
+    }
+
     /// Initiates a connection to the Service.
     pub fn connect(
         config: Config,
         service_config: ServiceConfig,
+        extensions: &Extensions,
         handle: &Handle,
     ) -> Box<Future<Item = Service, Error = ServiceConnectError>> {
         let client = Client::new(handle);
@@ -54,46 +138,77 @@ impl Service {
         ).parse()
             .expect("TODO Proper error handling");
         let mut request = Request::new(Method::Post, version_uri);
-        let our_version = ProtocolVersion {
-            major: 3,
-            minor: 0,
-            patch: 0,
-        };
         let sbn = ServiceBrokerNegotiation {
-            monto: our_version,
+            monto: config.broker.preferred_protocol(),
+            versions: config.broker.protocols.clone(),
             broker: config.version.clone().into(),
-            extensions: config.extensions.service.clone(),
+            extensions: supported_extensions(&config, extensions),
         };
+        let extensions = extensions.clone();
+        let preferred_format = config.broker.format;
         match serde_json::to_string(&sbn) {
             Ok(sbn) => request.set_body(sbn),
             Err(e) => return Box::new(err(e.into())),
         }
         request.headers_mut().set(ContentType::json());
+        request.headers_mut().set(accept_encoding());
         Box::new(
             client
                 .request(request)
                 .map_err(ServiceConnectError::from)
-                .and_then(|res| match res.status() {
-                    StatusCode::Ok => res.body().concat2().map_err(ServiceConnectError::from),
-                    _ => panic!("TODO Error handling"),
+                .and_then(move |res| {
+                    let status = res.status();
+                    let compress = accepts_gzip(res.headers().get());
+                    let format = preferred_format.if_accepted(res.headers().get());
+                    let encoding = res.headers().get::<ContentEncoding>().cloned();
+                    res.body()
+                        .concat2()
+                        .map_err(ServiceConnectError::from)
+                        .and_then(move |body| {
+                            decompress(body.to_vec(), encoding.as_ref())
+                                .map_err(ServiceConnectError::from)
+                        })
+                        .map(move |body| (status, body, (compress, format)))
                 })
-                .and_then(|body: Chunk| {
-                    result(serde_json::from_slice(body.as_ref())).map_err(ServiceConnectError::from)
+                .and_then(|(status, body, transfer): (StatusCode, Vec<u8>, (bool, Format))| {
+                    result(match status {
+                        // A Service responds to an incompatible Broker with a
+                        // BadRequest, but still includes its ServiceNegotiation.
+                        StatusCode::Ok | StatusCode::BadRequest => serde_json::from_slice(&body)
+                            .map(|sn| (sn, transfer))
+                            .map_err(ServiceConnectError::from),
+                        status => Err(ServiceConnectErrorKind::BadStatus(status).into()),
+                    })
                 })
-                .and_then(move |sn: ServiceNegotiation| {
-                    let version = min(our_version, sn.monto);
-                    let extensions = config
-                        .extensions
-                        .service
+                .and_then(move |(sn, (compress, format)): (ServiceNegotiation, (bool, Format))| {
+                    let ours = &config.broker.protocols;
+                    let version = match ProtocolVersion::negotiate(ours, &sn.supported()) {
+                        Some(version) => version,
+                        None => {
+                            let preferred = config.broker.preferred_protocol();
+                            return err(
+                                ServiceConnectErrorKind::NotCompatible(preferred, sn.monto).into(),
+                            );
+                        }
+                    };
+                    let enabled = supported_extensions(&config, &extensions)
                         .intersection(&sn.extensions)
                         .cloned()
                         .collect();
+                    let handlers = extensions.enabled(&BTreeSet::new(), &enabled);
                     ok(Service {
-                        client,
-                        config: service_config,
-                        extensions,
+                        extensions: enabled,
+                        handlers,
                         negotiation: sn,
                         protocol: version,
+                        recorder: None,
+                        transport: Transport::Http {
+                            client,
+                            config: service_config,
+                            compress,
+                            format,
+                            timeout: Duration::from_millis(config.broker.service_timeout),
+                        },
                     })
                 }),
         )
@@ -105,52 +220,166 @@ impl Service {
         identifier: ProductIdentifier,
         products: &[Product],
     ) -> Box<Future<Item = ServiceProduct, Error = RequestError>> {
-        let service_uri = format!(
-            "{}://{}{}/service",
-            self.config.scheme,
-            self.config.addr,
-            self.config.base
-        ).parse()
-            .expect("TODO Proper error handling");
-        let mut request = Request::new(Method::Post, service_uri);
         let br = BrokerRequest {
             request: identifier,
             products: products.to_owned(),
         };
-        match serde_json::to_string(&br) {
-            Ok(br) => request.set_body(br),
+        match self.recorder {
+            Some(ref recorder) => {
+                let recorder = recorder.clone();
+                let service = self.negotiation.service.id.clone();
+                Box::new(self.send(br.clone()).then(move |result| {
+                    recorder.record(&Record::ServiceExchange(ServiceExchange {
+                        service,
+                        request: br,
+                        response: ServiceResponse::from(&result),
+                    }));
+                    result
+                }))
+            }
+            None => self.send(br),
+        }
+    }
+
+    /// Tells the Service that a product it was asked for is no longer needed,
+    /// if it negotiated the `ServiceExtension::Cancel` extension. This is done
+    /// in the background, and errors are only logged.
+    pub fn cancel(&self, pi: &ProductIdentifier) {
+        let (client, config, format) = match self.transport {
+            Transport::Http {
+                ref client,
+                ref config,
+                format,
+                ..
+            } if self.extensions.contains(&ServiceExtension::Cancel) => {
+                (client, config, format)
+            }
+            _ => return,
+        };
+        let cancel_uri = match format!("{}://{}{}/cancel", config.scheme, config.addr, config.base)
+            .parse()
+        {
+            Ok(uri) => uri,
+            Err(e) => return error!("Couldn't make the cancellation URI: {}", e),
+        };
+        let mut request = Request::new(Method::Post, cancel_uri);
+        match format.serialize(pi) {
+            Ok(body) => request.set_body(body),
+            Err(e) => return error!("Couldn't serialize cancellation: {}", e),
+        }
+        request.headers_mut().set(ContentType(format.mime()));
+        let service = self.negotiation.service.id.clone();
+        client.handle().spawn(client.request(request).then(move |r| {
+            match r {
+                Ok(ref res) if res.status().is_success() => {}
+                Ok(res) => warn!("{} responded to a cancellation with {}", service, res.status()),
+                Err(e) => warn!("Couldn't send a cancellation to {}: {}", service, e),
+            }
+            Ok(())
+        }));
+    }
+
+    /// Sends a request to the Service.
+    fn send(&self, br: BrokerRequest) -> Box<Future<Item = ServiceProduct, Error = RequestError>> {
+        let (client, config, compress, format, timeout) = match self.transport {
+            Transport::Http {
+                ref client,
+                ref config,
+                compress,
+                format,
+                timeout,
+            } => (client, config, compress, format, timeout),
+            Transport::InProcess(ref service) => {
+                return Box::new(result(match service.borrow_mut().provide(br) {
+                    Ok(sp) => Ok(sp),
+                    Err(ProvideError::NotExposed(pi)) => {
+                        Err(RequestErrorKind::NotExposed(pi.into()).into())
+                    }
+                    Err(ProvideError::ServiceErrors(ses)) => {
+                        Err(RequestErrorKind::ServiceErrors(ses).into())
+                    }
+                }))
+            }
+            Transport::Replay(ref replay) => return Box::new(result(replay.respond(&br))),
+        };
+        let service_uri = format!("{}://{}{}/service", config.scheme, config.addr, config.base)
+            .parse()
+            .expect("TODO Proper error handling");
+        let mut request = Request::new(Method::Post, service_uri);
+        let mut body = match format.serialize(&br) {
+            Ok(body) => body,
             Err(e) => return Box::new(err(e.into())),
+        };
+        request.headers_mut().set(ContentType(format.mime()));
+        request.headers_mut().set(format.accept());
+        request.headers_mut().set(accept_encoding());
+        if compress {
+            body = gzip_if_large(body, request.headers_mut());
         }
-        request.headers_mut().set(ContentType::json());
-        Box::new(
-            self.client
+        request.set_body(body);
+        for handler in self.handlers.service() {
+            handler.request(&mut request);
+        }
+        let handlers = self.handlers.clone();
+        let response = Box::new(
+            client
                 .request(request)
                 .map_err(RequestError::from)
                 .and_then(|res| {
                     let status = res.status();
+                    let encoding = res.headers().get::<ContentEncoding>().cloned();
+                    let format = Format::from_content_type(res.headers().get());
                     res.body()
                         .concat2()
-                        .map(move |c| (status, c))
                         .map_err(RequestError::from)
+                        .and_then(move |c| {
+                            decompress(c.to_vec(), encoding.as_ref()).map_err(RequestError::from)
+                        })
+                        .map(move |c| (status, c, format))
                 })
-                .and_then(|(status, body)| {
+                .and_then(move |(status, body, format)| {
                     result(match status {
-                        StatusCode::Ok => {
-                            serde_json::from_slice(body.as_ref()).map_err(RequestError::from)
-                        }
-                        StatusCode::BadRequest => serde_json::from_slice(body.as_ref())
+                        StatusCode::Ok => format
+                            .deserialize(body.as_ref())
+                            .map_err(RequestError::from)
+                            .map(|mut sp: ServiceProduct| {
+                                for handler in handlers.service() {
+                                    handler.product(&mut sp.product);
+                                }
+                                sp
+                            }),
+                        StatusCode::BadRequest => format
+                            .deserialize(body.as_ref())
                             .map_err(RequestError::from)
                             .and_then(|pd| Err(RequestErrorKind::NotExposed(pd).into())),
-                        StatusCode::InternalServerError => serde_json::from_slice(body.as_ref())
+                        StatusCode::InternalServerError => format
+                            .deserialize(body.as_ref())
                             .map_err(RequestError::from)
                             .and_then(|ses| Err(RequestErrorKind::ServiceErrors(ses).into())),
-                        _ => panic!("TODO Proper error handling"),
+                        status => Err(RequestErrorKind::BadStatus(status).into()),
                     })
                 }),
-        )
+        );
+        let timeout = match Timeout::new(timeout, client.handle()) {
+            Ok(timeout) => timeout,
+            Err(e) => return Box::new(err(e.into())),
+        };
+        let timeout = timeout.then(|r| match r {
+            Ok(()) => Err(RequestErrorKind::TimedOut.into()),
+            Err(e) => Err(e.into()),
+        });
+        Box::new(response.select(timeout).map(|(sp, _)| sp).map_err(|(e, _)| e))
     }
 }
 
+/// Returns the Service Protocol Extensions the Broker supports.
+pub fn supported_extensions(config: &Config, extensions: &Extensions) -> BTreeSet<ServiceExtension> {
+    let mut supported = config.extensions.service.clone();
+    supported.extend(extensions.service_extensions());
+    supported.insert(ServiceExtension::Cancel);
+    supported
+}
+
 error_chain! {
     types {
         ServiceConnectError, ServiceConnectErrorKind, ServiceConnectResultExt;
@@ -160,15 +389,16 @@ error_chain! {
             #[doc = "An error from the network."];
         Serde(JsonError)
             #[doc = "An invalid response was received."];
+        Io(IoError)
+            #[doc = "A response couldn't be decompressed."];
         Uri(UriError)
             #[doc = "An invalid URI was created from the config"];
     }
     errors {
-        /// A status other than Ok was received from the Broker, indicating
-        /// that the Client is not compatible.
+        /// An unexpected status was received from the Service.
         BadStatus(code: StatusCode) {
-            description("The Broker is not compatible with this Client")
-            display("The Broker is not compatible with this Client: got {} from the Broker", code)
+            description("The Service responded with an unexpected status")
+            display("The Service responded with an unexpected status: {}", code)
         }
 
         /// The Broker and Service are not compatible.
@@ -188,6 +418,8 @@ error_chain! {
             #[doc = "An error from the network."];
         Serde(JsonError)
             #[doc = "An invalid response was received."];
+        Io(IoError)
+            #[doc = "A response couldn't be decompressed."];
         Uri(UriError)
             #[doc = "An invalid URI was created from the config"];
     }
@@ -202,5 +434,22 @@ error_chain! {
             description("Errors sent from the service")
             display("Errors sent from the service: {:?}", errors.errors.iter().format(", "))
         }
+
+        /// An unexpected status was received from the Service.
+        BadStatus(code: StatusCode) {
+            description("The Service responded with an unexpected status")
+            display("The Service responded with an unexpected status: {}", code)
+        }
+
+        /// The Service didn't respond in time.
+        TimedOut {
+            description("The Service didn't respond in time")
+        }
+
+        /// An error recorded in, or caused by, a replayed session.
+        Recorded(error: String) {
+            description("An error recorded in a session")
+            display("An error recorded in a session: {}", error)
+        }
     }
 }
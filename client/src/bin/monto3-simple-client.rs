@@ -74,6 +74,7 @@ fn main() {
             minor: 1,
             patch: 0,
         },
+        ..Config::default()
     };
     let client_handle = core.handle();
     let client = must(core.run(Client::new(config, client_handle)));
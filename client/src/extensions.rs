@@ -0,0 +1,21 @@
+//! Implementations of Client Protocol Extensions.
+
+use hyper::Request;
+
+use monto3_common::messages::Product;
+
+use messages::ClientExtension;
+
+/// An implementation of a Client Protocol Extension.
+///
+/// The hooks are only called if the Broker negotiated the extension.
+pub trait ExtensionHandler {
+    /// Returns the extension being implemented.
+    fn extension(&self) -> ClientExtension;
+
+    /// Modifies a request before it is sent to the Broker.
+    fn request(&self, _req: &mut Request) {}
+
+    /// Modifies a Product after it is received from the Broker.
+    fn product(&self, _product: &mut Product) {}
+}
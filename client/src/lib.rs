@@ -18,38 +18,97 @@ extern crate serde_json;
 extern crate tokio_core;
 extern crate url;
 
+pub mod extensions;
 pub mod messages;
 mod negotiation;
 
+use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
 
 use futures::{Future, Stream};
 use futures::future::{err, result};
-use hyper::{Get, Post, Put, Request, StatusCode, Uri};
-use hyper::header::{ContentLength, ContentType};
+use futures::stream::iter_result;
+use hyper::{Get, Method, Post, Put, Request, StatusCode, Uri};
+use hyper::header::{ContentEncoding, ContentLength, ContentType, ETag, EntityTag, IfNoneMatch,
+                    RetryAfter};
 use tokio_core::reactor::Handle;
 use url::Url;
 
+use monto3_common::encoding::{accept_encoding, decompress, gzip_if_large};
+use monto3_common::format::Format;
+use monto3_common::headers::MontoSession;
+use monto3_common::paths::absolute;
 use monto3_common::messages::{Identifier, Language, Product, ProductDescriptor, ProductIdentifier,
                               ProductName, ProtocolVersion, SoftwareVersion};
 use monto3_common::products::Source;
 
-use messages::{BrokerGetError, BrokerPutError, ClientNegotiation};
+use extensions::ExtensionHandler;
+use messages::{BatchRequest, BatchResponse, BrokerGetError, BrokerPutError, ClientExtension,
+               ClientNegotiation, QueryResponse, SourceEdit, SourcePatch, WorkspaceQuery};
 pub use negotiation::{Negotiation, NegotiationError, NegotiationErrorKind};
 
 type HttpClient = hyper::client::Client<hyper::client::HttpConnector>;
+type ProductCache = BTreeMap<(Identifier, ProductIdentifier), (EntityTag, Product)>;
 
 /// A Monto Client.
 pub struct Client {
     base_url: Url,
+    compress: bool,
+    extensions: BTreeSet<ClientExtension>,
+    format: Format,
+    handlers: Rc<Vec<Rc<ExtensionHandler>>>,
     http: HttpClient,
+    products: Rc<RefCell<ProductCache>>,
+    protocol: ProtocolVersion,
+    send_format: Format,
     services: BTreeMap<Identifier, BTreeSet<ProductDescriptor>>,
+    session: Option<String>,
+    source_versions: Rc<RefCell<BTreeMap<String, u64>>>,
 }
 
 impl Client {
+    /// Returns the Client Protocol Extensions negotiated with the Broker.
+    pub fn extensions(&self) -> &BTreeSet<ClientExtension> {
+        &self.extensions
+    }
+
+    /// Returns the Client Protocol version negotiated with the Broker.
+    pub fn protocol(&self) -> ProtocolVersion {
+        self.protocol
+    }
+
+    /// Sets a request body serialized in the format the Broker accepts,
+    /// compressing it if it is large and the Broker accepts compressed bodies.
+    fn set_body(&self, req: &mut Request, mut body: Vec<u8>) {
+        {
+            let headers = req.headers_mut();
+            headers.set(ContentLength(body.len() as u64));
+            headers.set(ContentType(self.send_format.mime()));
+            if self.compress {
+                body = gzip_if_large(body, headers);
+            }
+        }
+        req.set_body(body);
+    }
+
+    /// Adds the session header, `Accept` and `Accept-Encoding` to a request,
+    /// and lets the extension handlers modify it.
+    fn prepare(&self, req: &mut Request) {
+        req.headers_mut().set(self.format.accept());
+        req.headers_mut().set(accept_encoding());
+        if let Some(ref session) = self.session {
+            req.headers_mut().set(MontoSession(session.clone()));
+        }
+        for handler in self.handlers.iter() {
+            handler.request(req);
+        }
+    }
+
     /// Builds a Monto URI.
     ///
     /// TODO: This can be made more efficient when
@@ -96,14 +155,20 @@ impl Client {
         }
         debug!("base_url is {}", base_url);
 
+        let monto = match config.protocols.iter().next_back() {
+            Some(&monto) => monto,
+            None => return Negotiation::err(NegotiationErrorKind::NoProtocols.into()),
+        };
         let cn = ClientNegotiation {
-            monto: ProtocolVersion {
-                major: 3,
-                minor: 0,
-                patch: 0,
-            },
+            monto,
+            versions: config.protocols,
             client: config.version,
-            extensions: BTreeSet::new(),
+            extensions: config
+                .extensions
+                .iter()
+                .map(|h| h.extension())
+                .chain(Some(ClientExtension::Query))
+                .collect(),
         };
         let body = serde_json::to_string(&cn).unwrap();
 
@@ -114,56 +179,94 @@ impl Client {
         let mut req = Request::new(Post, url.to_string().parse().unwrap());
         req.headers_mut().set(ContentType::json());
         req.headers_mut().set(ContentLength(body.len() as u64));
+        req.headers_mut().set(accept_encoding());
         req.set_body(body);
 
         let http = HttpClient::new(&handle);
         let future = http.request(req);
-        Negotiation::new(base_url, http, cn, future)
+        Negotiation::new(base_url, http, cn, config.extensions, config.format, future)
     }
 
     /// Attempts to retrieve a Product from the Broker, as described in
     /// [Section 4.4](https://melt-umn.github.io/monto-v3-draft/draft03/#4-4-requesting-products)
     /// of the specification.
+    ///
+    /// The last copy of each Product received is kept, and the Broker is asked
+    /// to only send the Product again if it has changed.
     pub fn request(
         &mut self,
         service: &Identifier,
         pi: &ProductIdentifier,
     ) -> Box<Future<Item = Product, Error = RequestError>> {
-        let path: &Path = pi.path.as_ref();
-        let path = if path.is_absolute() {
-            path.to_owned()
-        } else {
-            match path.canonicalize() {
-                Ok(path) => path,
-                Err(e) => return Box::new(err(e.into())),
-            }
+        let path = match absolute(&pi.path) {
+            Ok(path) => path,
+            Err(e) => return Box::new(err(e.into())),
         };
-        let path = path.display().to_string();
 
-        let req = Request::new(
+        let mut req = Request::new(
             Get,
             self.make_uri(Some(service), &pi.name, Some(&pi.language), &path),
         );
+        let key = (
+            service.clone(),
+            ProductIdentifier {
+                name: pi.name.clone(),
+                language: pi.language.clone(),
+                path,
+            },
+        );
+        if let Some(&(ref tag, _)) = self.products.borrow().get(&key) {
+            req.headers_mut()
+                .set(IfNoneMatch::Items(vec![tag.clone()]));
+        }
+        self.prepare(&mut req);
         info!("Requesting product {:?} from {}", pi, service);
+        let handlers = self.handlers.clone();
+        let products = self.products.clone();
         Box::new(
             self.http
                 .request(req)
                 .map_err(RequestError::from)
                 .and_then(|res| {
                     let status = res.status();
+                    let tag = res.headers().get::<ETag>().map(|&ETag(ref tag)| tag.clone());
+                    let encoding = res.headers().get::<ContentEncoding>().cloned();
+                    let format = Format::from_content_type(res.headers().get());
+                    let retry = retry_after(res.headers());
                     res.body()
                         .concat2()
-                        .map(move |b| (b, status))
                         .map_err(RequestError::from)
+                        .and_then(move |b| {
+                            decompress(b.to_vec(), encoding.as_ref()).map_err(RequestError::from)
+                        })
+                        .map(move |b| (b, status, tag, format, retry))
                 })
-                .and_then(|(body, status)| {
+                .and_then(move |(body, status, tag, format, retry)| {
                     result(match status {
-                        StatusCode::Ok => {
-                            serde_json::from_slice(body.as_ref()).map_err(RequestError::from)
+                        StatusCode::Ok => format
+                            .deserialize(body.as_ref())
+                            .map_err(RequestError::from)
+                            .map(|mut product: Product| {
+                                for handler in handlers.iter() {
+                                    handler.product(&mut product);
+                                }
+                                let mut products = products.borrow_mut();
+                                match tag {
+                                    Some(tag) => products.insert(key, (tag, product.clone())),
+                                    None => products.remove(&key),
+                                };
+                                product
+                            }),
+                        StatusCode::NotModified => match products.borrow().get(&key) {
+                            Some(&(_, ref product)) => Ok(product.clone()),
+                            None => Err(RequestErrorKind::BadStatus(status).into()),
+                        },
+                        StatusCode::TooManyRequests => {
+                            Err(RequestErrorKind::RateLimited(retry).into())
                         }
                         _ => {
                             let e =
-                                RequestError::from(match serde_json::from_slice(body.as_ref()) {
+                                RequestError::from(match format.deserialize(body.as_ref()) {
                                     Ok(bge) => RequestErrorKind::Broker(bge),
                                     Err(err) => RequestErrorKind::Json(err),
                                 });
@@ -174,6 +277,154 @@ impl Client {
         )
     }
 
+    /// Retrieves several Products from the Broker with a single request.
+    ///
+    /// The results are returned in the same order as the requests. Unlike
+    /// `request`, a copy of the Products is not kept.
+    pub fn request_many(
+        &mut self,
+        requests: &[(Identifier, ProductIdentifier)],
+    ) -> Box<Future<Item = Vec<Result<Product, BrokerGetError>>, Error = RequestError>> {
+        let mut batch = Vec::with_capacity(requests.len());
+        for &(ref service, ref pi) in requests {
+            let path = match absolute(&pi.path) {
+                Ok(path) => path,
+                Err(e) => return Box::new(err(e.into())),
+            };
+            batch.push(BatchRequest {
+                service: service.clone(),
+                product: ProductIdentifier {
+                    name: pi.name.clone(),
+                    language: pi.language.clone(),
+                    path,
+                },
+            });
+        }
+
+        let body = match self.send_format.serialize(&batch) {
+            Ok(body) => body,
+            Err(e) => return Box::new(err(RequestError::from(e))),
+        };
+        let url = self.base_url
+            .join("products")
+            .expect("Illegal internal Client state -- base_url is cannot-be-a-base");
+        let mut req = Request::new(Post, url.into_string().parse().unwrap());
+        self.set_body(&mut req, body);
+        self.prepare(&mut req);
+        info!("Requesting {} products", batch.len());
+        let handlers = self.handlers.clone();
+        Box::new(
+            self.http
+                .request(req)
+                .map_err(RequestError::from)
+                .and_then(|res| {
+                    let status = res.status();
+                    let encoding = res.headers().get::<ContentEncoding>().cloned();
+                    let format = Format::from_content_type(res.headers().get());
+                    let retry = retry_after(res.headers());
+                    res.body()
+                        .concat2()
+                        .map_err(RequestError::from)
+                        .and_then(move |b| {
+                            decompress(b.to_vec(), encoding.as_ref()).map_err(RequestError::from)
+                        })
+                        .map(move |b| (b, status, format, retry))
+                })
+                .and_then(move |(body, status, format, retry)| {
+                    result(match status {
+                        StatusCode::Ok => format
+                            .deserialize(body.as_ref())
+                            .map_err(RequestError::from)
+                            .map(|brs: Vec<BatchResponse>| {
+                                brs.into_iter()
+                                    .map(|br| match br {
+                                        BatchResponse::Product(mut product) => {
+                                            for handler in handlers.iter() {
+                                                handler.product(&mut product);
+                                            }
+                                            Ok(product)
+                                        }
+                                        BatchResponse::Error(err) => Err(err),
+                                    })
+                                    .collect()
+                            }),
+                        StatusCode::TooManyRequests => {
+                            Err(RequestErrorKind::RateLimited(retry).into())
+                        }
+                        _ => Err(RequestErrorKind::BadStatus(status).into()),
+                    })
+                }),
+        )
+    }
+
+    /// Retrieves a Product for every file under a directory, as described by
+    /// `WorkspaceQuery`. The responses are streamed back as the Broker
+    /// resolves each one. Unlike `request`, a copy of the Products is not
+    /// kept. The Broker must have negotiated the `ClientExtension::Query`
+    /// extension.
+    pub fn query(
+        &mut self,
+        query: &WorkspaceQuery,
+    ) -> Box<Stream<Item = QueryResponse, Error = RequestError>> {
+        if !self.extensions.contains(&ClientExtension::Query) {
+            let e = RequestErrorKind::NotNegotiated(ClientExtension::Query);
+            return Box::new(err(RequestError::from(e)).into_stream());
+        }
+        let body = match self.send_format.serialize(query) {
+            Ok(body) => body,
+            Err(e) => return Box::new(err(RequestError::from(e)).into_stream()),
+        };
+        let url = self.base_url
+            .join("query")
+            .expect("Illegal internal Client state -- base_url is cannot-be-a-base");
+        let mut req = Request::new(Post, url.into_string().parse().unwrap());
+        self.set_body(&mut req, body);
+        self.prepare(&mut req);
+        info!("Querying {} under {}", query.product, query.root);
+        let handlers = self.handlers.clone();
+        Box::new(
+            self.http
+                .request(req)
+                .map_err(RequestError::from)
+                .and_then(|res| match res.status() {
+                    StatusCode::Ok => Ok(res),
+                    StatusCode::TooManyRequests => {
+                        Err(RequestErrorKind::RateLimited(retry_after(res.headers())).into())
+                    }
+                    status => Err(RequestErrorKind::BadStatus(status).into()),
+                })
+                .map(move |res| {
+                    // Each response is a line of JSON, but lines may be split
+                    // across chunks.
+                    let mut buf = Vec::new();
+                    res.body()
+                        .map_err(RequestError::from)
+                        .map(move |chunk| {
+                            buf.extend_from_slice(&chunk);
+                            let mut responses = Vec::new();
+                            while let Some(i) = buf.iter().position(|&b| b == b'\n') {
+                                let line = buf.drain(..i + 1).collect::<Vec<_>>();
+                                let response = serde_json::from_slice(&line)
+                                    .map_err(RequestError::from)
+                                    .map(|response| match response {
+                                        QueryResponse::Product(mut product) => {
+                                            for handler in handlers.iter() {
+                                                handler.product(&mut product);
+                                            }
+                                            QueryResponse::Product(product)
+                                        }
+                                        response => response,
+                                    });
+                                responses.push(response);
+                            }
+                            iter_result(responses)
+                        })
+                        .flatten()
+                })
+                .flatten_stream(),
+        )
+    }
+
     /// Returns an iterator over the Products that can be requested by the Client.
     pub fn products(&self) -> ProductsIter {
         let iter = self.services.iter().flat_map(|(service, products)| {
@@ -219,6 +470,10 @@ impl Client {
     /// Sends a Product to the Broker, as described in
     /// [Section 4.3](https://melt-umn.github.io/monto-v3-draft/draft03/#4-3-sending-products)
     /// of the specification.
+    ///
+    /// The path may be a file that doesn't exist yet, or a URI for a document
+    /// that isn't a file, such as `untitled:Untitled-1`, as described in
+    /// `monto3_common::paths`.
     pub fn send_product<P: Into<Product>>(
         &mut self,
         p: P,
@@ -229,50 +484,141 @@ impl Client {
             path,
             value,
         } = p.into();
-        let path = PathBuf::from(path);
-        let path = if path.is_absolute() {
-            path
-        } else {
-            match path.canonicalize() {
-                Ok(path) => path,
-                Err(e) => return Box::new(err(e.into())),
-            }
+        let path = match absolute(&path) {
+            Ok(path) => path,
+            Err(e) => return Box::new(err(e.into())),
         };
-        let path = path.display().to_string();
 
-        let body = match serde_json::to_string(&value) {
+        let body = match self.send_format.serialize(&value) {
             Ok(body) => body,
             Err(e) => return Box::new(err(SendError::from(e))),
         };
         let mut req = Request::new(Put, self.make_uri(None, &name, Some(&language), &path));
-        {
-            let headers = req.headers_mut();
-            headers.set(ContentLength(body.len() as u64));
-            headers.set(ContentType::json());
-        }
-        req.set_body(body);
+        self.set_body(&mut req, body);
+        self.prepare(&mut req);
+        let source_versions = self.source_versions.clone();
+        let is_source = name == ProductName::Source;
         Box::new(
             self.http
                 .request(req)
                 .and_then(|r| {
                     let status = r.status();
-                    r.body().concat2().map(move |b| (b, status))
+                    let version = version_of(r.headers());
+                    let format = Format::from_content_type(r.headers().get());
+                    let retry = retry_after(r.headers());
+                    r.body().concat2().map(move |b| (b, status, version, format, retry))
                 })
                 .map_err(SendError::from)
-                .and_then(|(body, status)| {
+                .and_then(move |(body, status, version, format, retry)| {
                     result(match status {
-                        StatusCode::NoContent => Ok(()),
+                        StatusCode::NoContent => {
+                            if let (true, Some(version)) = (is_source, version) {
+                                source_versions.borrow_mut().insert(path, version);
+                            }
+                            Ok(())
+                        }
                         StatusCode::BadRequest => {
-                            Err(match serde_json::from_slice(body.as_ref()) {
+                            Err(match format.deserialize(body.as_ref()) {
                                 Ok(bpe) => SendErrorKind::Broker(bpe).into(),
                                 Err(err) => SendError::from(err),
                             })
                         }
-                        _ => panic!("TODO Proper error handling"),
+                        StatusCode::TooManyRequests => {
+                            Err(SendErrorKind::RateLimited(retry).into())
+                        }
+                        status => Err(SendErrorKind::BadStatus(status).into()),
                     })
                 }),
         )
     }
+
+    /// Sends edits to a source Product the Broker already has, instead of
+    /// sending the whole file again.
+    ///
+    /// The edits must have been made against the version of the source last
+    /// sent to the Broker by this Client, with either `send_file`,
+    /// `send_product`, or `send_edits`.
+    pub fn send_edits<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        language: Language,
+        edits: Vec<SourceEdit>,
+    ) -> Box<Future<Item = (), Error = SendError>> {
+        let path = match absolute(&path.as_ref().to_string_lossy()) {
+            Ok(path) => path,
+            Err(e) => return Box::new(err(e.into())),
+        };
+
+        let version = match self.source_versions.borrow().get(&path) {
+            Some(&version) => version,
+            None => return Box::new(err(SendErrorKind::NoBaseVersion(path).into())),
+        };
+        let body = match self.send_format.serialize(&SourcePatch { version, edits }) {
+            Ok(body) => body,
+            Err(e) => return Box::new(err(SendError::from(e))),
+        };
+        let mut req = Request::new(
+            Method::Patch,
+            self.make_uri(None, &ProductName::Source, Some(&language), &path),
+        );
+        self.set_body(&mut req, body);
+        self.prepare(&mut req);
+        let source_versions = self.source_versions.clone();
+        Box::new(
+            self.http
+                .request(req)
+                .and_then(|r| {
+                    let status = r.status();
+                    let version = version_of(r.headers());
+                    let format = Format::from_content_type(r.headers().get());
+                    let retry = retry_after(r.headers());
+                    r.body().concat2().map(move |b| (b, status, version, format, retry))
+                })
+                .map_err(SendError::from)
+                .and_then(move |(body, status, version, format, retry)| {
+                    result(match status {
+                        StatusCode::NoContent => {
+                            let mut source_versions = source_versions.borrow_mut();
+                            match version {
+                                Some(version) => source_versions.insert(path, version),
+                                None => source_versions.remove(&path),
+                            };
+                            Ok(())
+                        }
+                        StatusCode::BadRequest | StatusCode::Conflict => {
+                            Err(match format.deserialize(body.as_ref()) {
+                                Ok(bpe) => SendErrorKind::Broker(bpe).into(),
+                                Err(err) => SendError::from(err),
+                            })
+                        }
+                        StatusCode::TooManyRequests => {
+                            Err(SendErrorKind::RateLimited(retry).into())
+                        }
+                        status => Err(SendErrorKind::BadStatus(status).into()),
+                    })
+                }),
+        )
+    }
+}
+
+/// Reads the version of a source from the `ETag` header of the Broker's
+/// response to sending it.
+fn version_of(headers: &hyper::Headers) -> Option<u64> {
+    headers
+        .get::<ETag>()
+        .and_then(|&ETag(ref tag)| tag.tag().parse().ok())
+}
+
+/// Reads how long the Broker asked for the Client to wait before retrying a
+/// request from the `Retry-After` header.
+fn retry_after(headers: &hyper::Headers) -> Option<Duration> {
+    match headers.get() {
+        Some(&RetryAfter::Delay(delay)) => Some(delay),
+        Some(&RetryAfter::DateTime(date)) => SystemTime::from(date)
+            .duration_since(SystemTime::now())
+            .ok(),
+        None => None,
+    }
 }
 
 /// Configuration for a Client.
@@ -293,13 +639,34 @@ pub struct Config {
 
     /// The name and version of the client.
     pub version: SoftwareVersion,
+
+    /// The handlers for the Client Protocol Extensions the Client supports.
+    ///
+    /// Defaults to none.
+    pub extensions: Vec<Rc<ExtensionHandler>>,
+
+    /// The versions of the Client Protocol the Client supports. The highest
+    /// version shared with the Broker is used.
+    ///
+    /// Defaults to all the versions this library supports.
+    pub protocols: BTreeSet<ProtocolVersion>,
+
+    /// The format to send products to the Broker in, and to ask for products
+    /// from it in. JSON is used instead if the Broker doesn't support this
+    /// format.
+    ///
+    /// Defaults to JSON.
+    pub format: Format,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
+            extensions: Vec::new(),
+            format: Format::Json,
             host: "localhost".to_owned(),
             port: 28888,
+            protocols: ProtocolVersion::supported(),
             version: SoftwareVersion {
                 id: "edu.umn.cs.melt.monto_rs.client".parse().unwrap(),
                 name: None,
@@ -340,6 +707,30 @@ error_chain! {
         Json(serde_json::Error)
             #[doc = "An invalid response (bad JSON) was received from the Broker."];
     }
+    errors {
+        /// An unexpected status was received from the Broker.
+        BadStatus(code: StatusCode) {
+            description("An unexpected status was received from the Broker")
+            display("An unexpected status was received from the Broker: {}", code)
+        }
+
+        /// The Broker refused the request because the Client has made too
+        /// many. If the Broker said when to retry, the delay is included.
+        RateLimited(retry_after: Option<Duration>) {
+            description("The Broker refused the request because too many were made")
+            display("The Broker refused the request because too many were made{}",
+                    retry_after.map(|d| format!("; retry after {}s", d.as_secs()))
+                        .unwrap_or_default())
+        }
+
+        /// The request needs an extension that wasn't negotiated with the
+        /// Broker.
+        NotNegotiated(extension: ClientExtension) {
+            description("The request needs an extension that wasn't negotiated")
+            display("The request needs the {} extension, which wasn't negotiated",
+                    extension.name())
+        }
+    }
 }
 
 error_chain! {
@@ -356,4 +747,27 @@ error_chain! {
         Json(serde_json::Error)
             #[doc = "An invalid response (bad JSON) was received from the Broker."];
     }
+    errors {
+        /// Edits were sent for a source that has not been sent to the Broker
+        /// by this Client.
+        NoBaseVersion(path: String) {
+            description("No version of the source has been sent to the Broker")
+            display("No version of {} has been sent to the Broker", path)
+        }
+
+        /// An unexpected status was received from the Broker.
+        BadStatus(code: StatusCode) {
+            description("An unexpected status was received from the Broker")
+            display("An unexpected status was received from the Broker: {}", code)
+        }
+
+        /// The Broker refused the request because the Client has made too
+        /// many. If the Broker said when to retry, the delay is included.
+        RateLimited(retry_after: Option<Duration>) {
+            description("The Broker refused the request because too many were made")
+            display("The Broker refused the request because too many were made{}",
+                    retry_after.map(|d| format!("; retry after {}s", d.as_secs()))
+                        .unwrap_or_default())
+        }
+    }
 }
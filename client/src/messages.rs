@@ -6,8 +6,10 @@ use std::collections::BTreeSet;
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
-use monto3_common::messages::{Identifier, NamespacedName, ProductIdentifier, ProtocolVersion,
-                              SoftwareVersion};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use monto3_common::messages::{Identifier, Language, NamespacedName, Product,
+                              ProductIdentifier, ProductName, ProtocolVersion, SoftwareVersion};
 use monto3_service::messages::ServiceNegotiation;
 
 /// The Message that a Client sends to a Broker during version negotiation.
@@ -20,6 +22,12 @@ pub struct ClientNegotiation {
     /// The version of the Client Protocol the Client supports.
     pub monto: ProtocolVersion,
 
+    /// Other versions of the Client Protocol the Client supports, in addition to
+    /// `monto`. This is not part of the specification, so it may be absent, in
+    /// which case only `monto` is supported.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub versions: BTreeSet<ProtocolVersion>,
+
     /// The version information of the Client.
     pub client: SoftwareVersion,
 
@@ -28,6 +36,15 @@ pub struct ClientNegotiation {
     pub extensions: BTreeSet<ClientExtension>,
 }
 
+impl ClientNegotiation {
+    /// Returns all the versions of the Client Protocol the Client supports.
+    pub fn supported(&self) -> BTreeSet<ProtocolVersion> {
+        let mut versions = self.versions.clone();
+        versions.insert(self.monto);
+        versions
+    }
+}
+
 /// The Message that a Broker sends to a Client during version negotiation.
 ///
 /// Defined in
@@ -35,9 +52,16 @@ pub struct ClientNegotiation {
 /// of the specification.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ClientBrokerNegotiation {
-    /// The version of the Client Protocol the Broker supports.
+    /// The version of the Client Protocol the Broker chose to use, or the highest
+    /// version it supports if none are compatible.
     pub monto: ProtocolVersion,
 
+    /// Other versions of the Client Protocol the Broker supports, in addition to
+    /// `monto`. This is not part of the specification, so it may be absent, in
+    /// which case only `monto` is supported.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub versions: BTreeSet<ProtocolVersion>,
+
     /// The version information of the Broker.
     pub broker: SoftwareVersion,
 
@@ -49,14 +73,154 @@ pub struct ClientBrokerNegotiation {
     pub services: BTreeSet<ServiceNegotiation>,
 }
 
-/// An extension to the Client Protocol.
-#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
-#[serde(rename_all = "snake_case", untagged)]
+impl ClientBrokerNegotiation {
+    /// Returns all the versions of the Client Protocol the Broker supports.
+    pub fn supported(&self) -> BTreeSet<ProtocolVersion> {
+        let mut versions = self.versions.clone();
+        versions.insert(self.monto);
+        versions
+    }
+}
+
+/// An extension to the Client Protocol. Extensions are negotiated by their
+/// names.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum ClientExtension {
-    /// An unknown and unsupported extension.
+    /// The extension for requesting a product for every file under a
+    /// directory at once. The Client sends a `POST` to `/monto/query` with a
+    /// `WorkspaceQuery` as the body, and the Broker responds with a
+    /// `QueryResponse` per line.
+    Query,
+
+    /// An extension known only by its name. Extensions implemented by an
+    /// `ExtensionHandler` are also negotiated by name, so they use this too.
     Unknown(NamespacedName),
 }
 
+impl ClientExtension {
+    /// Returns the name the extension is negotiated under.
+    pub fn name(&self) -> NamespacedName {
+        match *self {
+            ClientExtension::Query => NamespacedName::monto("query"),
+            ClientExtension::Unknown(ref name) => name.clone(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientExtension {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        NamespacedName::deserialize(d).map(ClientExtension::from)
+    }
+}
+
+impl From<NamespacedName> for ClientExtension {
+    fn from(name: NamespacedName) -> ClientExtension {
+        if name == NamespacedName::monto("query") {
+            ClientExtension::Query
+        } else {
+            ClientExtension::Unknown(name)
+        }
+    }
+}
+
+impl Serialize for ClientExtension {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.name().serialize(s)
+    }
+}
+
+/// A single request for a Product in a batch, sent from a Client to the Broker.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct BatchRequest {
+    /// The Service to request the Product from.
+    pub service: Identifier,
+
+    /// The Product being requested.
+    pub product: ProductIdentifier,
+}
+
+/// The Broker's response to a single request in a batch.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(content = "value", rename_all = "snake_case", tag = "type")]
+pub enum BatchResponse {
+    /// The Product that was requested.
+    Product(Product),
+
+    /// The error that occurred when requesting the Product.
+    Error(BrokerGetError),
+}
+
+/// A request for a Product for every file under a directory, sent from a
+/// Client to the Broker.
+///
+/// Each file's language is `language` if it is given, or else is guessed from
+/// the file's extension; files whose language can't be guessed are skipped.
+/// If `glob` is given, only files whose paths relative to `root` match it are
+/// included. Otherwise, if `language` is given, only files guessed to be in
+/// that language are.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct WorkspaceQuery {
+    /// The Service to request the Products from.
+    pub service: Identifier,
+
+    /// The Product to request for each file.
+    pub product: ProductName,
+
+    /// The directory to search for files under.
+    pub root: String,
+
+    /// The language to request the Products in.
+    #[serde(default)]
+    pub language: Option<Language>,
+
+    /// A glob that the paths of files must match.
+    #[serde(default)]
+    pub glob: Option<String>,
+}
+
+/// The Broker's response for a single file in a workspace query.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(content = "value", rename_all = "snake_case", tag = "type")]
+pub enum QueryResponse {
+    /// The Product for the file.
+    Product(Product),
+
+    /// The error that occurred when requesting the Product for the file.
+    Error {
+        /// The Product that was requested.
+        product: ProductIdentifier,
+
+        /// The error that occurred.
+        error: BrokerGetError,
+    },
+}
+
+/// The Message that a Client sends to the Broker to edit a source Product in
+/// place, rather than sending the whole file again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SourcePatch {
+    /// The version of the source the edits were made against, as given in the
+    /// `ETag` of the Broker's response to the last PUT or PATCH of the source.
+    pub version: u64,
+
+    /// The edits to make. Each edit is applied to the result of the ones
+    /// before it.
+    pub edits: Vec<SourceEdit>,
+}
+
+/// A replacement of a range of bytes in a source Product.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct SourceEdit {
+    /// The first byte being replaced.
+    pub start_byte: usize,
+
+    /// The byte after the last byte being replaced.
+    pub end_byte: usize,
+
+    /// The text to replace the bytes with.
+    pub text: String,
+}
+
 /// An error that occurs during the sending of a product from a Client to the Broker.
 ///
 /// Defined in
@@ -67,6 +231,16 @@ pub enum ClientExtension {
 pub enum BrokerPutError {
     /// A language was not provided, and it could not be detected by the Broker.
     NoLanguage,
+
+    /// A SourcePatch was sent against a version of the source other than the
+    /// one the Broker has.
+    VersionMismatch {
+        /// The version of the source the Broker has, if it has one at all.
+        current: Option<u64>,
+    },
+
+    /// An edit in a SourcePatch did not fit the source it was applied to.
+    BadEdit(SourceEdit),
 }
 
 impl Display for BrokerPutError {
@@ -75,6 +249,20 @@ impl Display for BrokerPutError {
             BrokerPutError::NoLanguage => fmt.write_str(
                 "No language was provided, and it could not be detected by the Broker.",
             ),
+            BrokerPutError::VersionMismatch { current: Some(current) } => write!(
+                fmt,
+                "The edits were not made against the current version of the source ({})",
+                current
+            ),
+            BrokerPutError::VersionMismatch { current: None } => {
+                fmt.write_str("The Broker does not have a version of the source to edit")
+            }
+            BrokerPutError::BadEdit(ref edit) => write!(
+                fmt,
+                "The edit of bytes {} to {} does not fit the source",
+                edit.start_byte,
+                edit.end_byte
+            ),
         }
     }
 }
@@ -85,6 +273,10 @@ impl Error for BrokerPutError {
             BrokerPutError::NoLanguage => {
                 "No language was provided, and it could not be detected by the Broker."
             }
+            BrokerPutError::VersionMismatch { .. } => {
+                "The edits were not made against the current version of the source"
+            }
+            BrokerPutError::BadEdit(_) => "An edit does not fit the source",
         }
     }
 }
@@ -123,6 +315,11 @@ pub enum BrokerGetError {
 
     /// A dependency was unresolvable.
     Unresolvable(ProductIdentifier),
+
+    /// A newer version of the source at the Product's path was sent to the
+    /// Broker while the Product was being computed, so the Product was
+    /// discarded. Requesting it again will compute it from the new version.
+    SourceChanged,
 }
 
 impl Display for BrokerGetError {
@@ -145,6 +342,9 @@ impl Display for BrokerGetError {
             BrokerGetError::Unresolvable(ref pi) => {
                 write!(fmt, "A product was unresolvable: {:?}", pi)
             }
+            BrokerGetError::SourceChanged => {
+                fmt.write_str("The source changed while the Product was being computed")
+            }
         }
     }
 }
@@ -159,6 +359,9 @@ impl Error for BrokerGetError {
             BrokerGetError::ServiceError { .. } => "An error from a service",
             BrokerGetError::ServiceConnectError { .. } => "An error trying to connect to a Service",
             BrokerGetError::Unresolvable(_) => "A product was unresolvable",
+            BrokerGetError::SourceChanged => {
+                "The source changed while the Product was being computed"
+            }
         }
     }
 }
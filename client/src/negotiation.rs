@@ -1,13 +1,22 @@
+use std::collections::BTreeSet;
+use std::io::Error as IoError;
+use std::rc::Rc;
+
 use futures::{Future, Poll, Stream};
 use hyper;
-use hyper::StatusCode;
+use hyper::{Headers, StatusCode};
+use hyper::header::ContentEncoding;
 use hyper::client::FutureResponse;
 use serde_json;
 use url::{ParseError as UrlError, Url};
 
+use monto3_common::encoding::{accepts_gzip, decompress};
+use monto3_common::format::Format;
+use monto3_common::headers::MontoSession;
 use monto3_common::messages::ProtocolVersion;
 
 use {Client, HttpClient};
+use extensions::ExtensionHandler;
 use messages::{ClientBrokerNegotiation, ClientNegotiation};
 
 /// A Future for a Client negotiating version information and establishing a
@@ -22,15 +31,31 @@ impl Negotiation {
         base_url: Url,
         client: HttpClient,
         cn: ClientNegotiation,
+        handlers: Vec<Rc<ExtensionHandler>>,
+        format: Format,
         future: FutureResponse,
     ) -> Negotiation {
         let inner = future
             .map_err(NegotiationError::from)
-            .and_then(|res| res.body().concat2().map_err(NegotiationError::from))
-            .and_then(|body| {
-                serde_json::from_slice(body.as_ref()).map_err(NegotiationError::from)
+            .and_then(|res| {
+                let headers = res.headers().clone();
+                let encoding = res.headers().get::<ContentEncoding>().cloned();
+                res.body()
+                    .concat2()
+                    .map_err(NegotiationError::from)
+                    .and_then(move |body| {
+                        decompress(body.to_vec(), encoding.as_ref()).map_err(NegotiationError::from)
+                    })
+                    .map(move |body| (body, headers))
+            })
+            .and_then(|(body, headers)| {
+                serde_json::from_slice(&body)
+                    .map(|cbn| (cbn, headers))
+                    .map_err(NegotiationError::from)
             })
-            .and_then(|cbn| Negotiation::negotiate(base_url, client, cn, cbn));
+            .and_then(move |(cbn, headers)| {
+                Negotiation::negotiate(base_url, client, cn, handlers, cbn, &headers, format)
+            });
         Negotiation {
             inner: Box::new(inner),
         }
@@ -48,17 +73,39 @@ impl Negotiation {
         base_url: Url,
         http: HttpClient,
         cn: ClientNegotiation,
+        handlers: Vec<Rc<ExtensionHandler>>,
         cbn: ClientBrokerNegotiation,
+        headers: &Headers,
+        format: Format,
     ) -> Result<Client, NegotiationError> {
-        if cn.monto.compatible(&cbn.monto) {
+        if let Some(protocol) = ProtocolVersion::negotiate(&cn.supported(), &cbn.supported()) {
+            let extensions = cbn.extensions
+                .intersection(&cn.extensions)
+                .cloned()
+                .collect::<BTreeSet<_>>();
             let services = cbn.services
                 .into_iter()
                 .map(|sn| (sn.service.id, sn.products))
                 .collect();
+            let handlers = handlers
+                .into_iter()
+                .filter(|h| extensions.contains(&h.extension()))
+                .collect();
             Ok(Client {
                 base_url,
+                compress: accepts_gzip(headers.get()),
+                extensions,
+                format,
+                handlers: Rc::new(handlers),
                 http,
+                products: Default::default(),
+                protocol,
+                send_format: format.if_accepted(headers.get()),
                 services,
+                session: headers
+                    .get()
+                    .map(|&MontoSession(ref token)| token.clone()),
+                source_versions: Default::default(),
             })
         } else {
             Err(NegotiationErrorKind::NotCompatible(cn.monto, cbn.monto).into())
@@ -84,6 +131,8 @@ error_chain! {
     foreign_links {
         Hyper(hyper::Error)
             #[doc = "An error from the network."];
+        Io(IoError)
+            #[doc = "A response couldn't be decompressed."];
         Serde(serde_json::Error)
             #[doc = "An invalid response was received."];
     }
@@ -100,6 +149,11 @@ error_chain! {
             description("The config was invalid")
         }
 
+        /// The config did not list any protocol versions to use.
+        NoProtocols {
+            description("The config did not list any protocol versions")
+        }
+
         /// The Client and Broker are not compatible.
         NotCompatible(client: ProtocolVersion, broker: ProtocolVersion) {
             description("The Broker and Client are not compatible")
@@ -0,0 +1,151 @@
+//! Compression of message bodies.
+//!
+//! Only gzip is supported. A response is compressed if the request's
+//! `Accept-Encoding` allows it, and every response carries an
+//! `Accept-Encoding` header advertising that the sender can also decompress
+//! request bodies, as in [RFC 7694](https://tools.ietf.org/html/rfc7694).
+
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::{Future, Stream};
+use hyper::{Error as HyperError, Headers, Response};
+use hyper::header::{qitem, AcceptEncoding, ContentEncoding, ContentLength, ETag, Encoding,
+                    EntityTag};
+
+/// Bodies smaller than this many bytes are never compressed, as the savings
+/// wouldn't be worth the time.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Bodies that decompress to more than this many bytes are rejected, so a
+/// small compressed body can't exhaust memory.
+pub const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Returns whether the given `Accept-Encoding` header allows gzip.
+pub fn accepts_gzip(accept: Option<&AcceptEncoding>) -> bool {
+    match accept {
+        Some(&AcceptEncoding(ref encodings)) => encodings
+            .iter()
+            .any(|e| e.item == Encoding::Gzip && e.quality > Default::default()),
+        None => false,
+    }
+}
+
+/// Returns an `Accept-Encoding` header listing the supported encodings.
+pub fn accept_encoding() -> AcceptEncoding {
+    AcceptEncoding(vec![qitem(Encoding::Gzip)])
+}
+
+/// Compresses a body with gzip.
+pub fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(bytes)
+        .and_then(|()| encoder.finish())
+        .expect("Writing to a Vec can't fail")
+}
+
+/// Compresses a body with gzip if it is large enough, setting the
+/// `Content-Encoding` and `Content-Length` headers if it is compressed.
+pub fn gzip_if_large(bytes: Vec<u8>, headers: &mut Headers) -> Vec<u8> {
+    if bytes.len() < COMPRESSION_THRESHOLD {
+        return bytes;
+    }
+    let compressed = gzip(&bytes);
+    headers.set(ContentEncoding(vec![Encoding::Gzip]));
+    headers.set(ContentLength(compressed.len() as u64));
+    compressed
+}
+
+/// Undoes the encodings listed in a `Content-Encoding` header. An error is
+/// returned if the body decompresses to more than `MAX_DECOMPRESSED_SIZE`
+/// bytes.
+pub fn decompress(bytes: Vec<u8>, encoding: Option<&ContentEncoding>) -> IoResult<Vec<u8>> {
+    let encodings = match encoding {
+        Some(&ContentEncoding(ref encodings)) => encodings,
+        None => return Ok(bytes),
+    };
+    let mut bytes = bytes;
+    for encoding in encodings.iter().rev() {
+        match *encoding {
+            Encoding::Identity => {}
+            Encoding::Gzip => {
+                let mut out = Vec::new();
+                GzDecoder::new(&bytes[..])
+                    .take(MAX_DECOMPRESSED_SIZE + 1)
+                    .read_to_end(&mut out)?;
+                if out.len() as u64 > MAX_DECOMPRESSED_SIZE {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidData,
+                        "Body is too large when decompressed",
+                    ));
+                }
+                bytes = out;
+            }
+            ref encoding => {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    format!("Unsupported Content-Encoding: {}", encoding),
+                ))
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+/// Compresses a Response if the request allowed it and the Response's body is
+/// large enough. An `Accept-Encoding` header is added to the Response either
+/// way.
+///
+/// Responses without a `Content-Length` are assumed to be streamed, and are
+/// never compressed, since that would mean waiting for the whole body. If a
+/// compressed Response has a strong `ETag`, it is made weak, since the
+/// compressed body isn't byte-for-byte the same representation.
+pub fn compress_response(
+    res: Response,
+    accept: Option<&AcceptEncoding>,
+) -> Box<Future<Item = Response, Error = HyperError>> {
+    let res = res.with_header(accept_encoding());
+    if !accepts_gzip(accept) || res.headers().has::<ContentEncoding>()
+        || !res.headers().has::<ContentLength>()
+    {
+        return Box::new(::futures::future::ok(res));
+    }
+    let status = res.status();
+    let mut headers = res.headers().clone();
+    Box::new(res.body().concat2().map(move |body| {
+        let body = gzip_if_large(body.to_vec(), &mut headers);
+        if headers.has::<ContentEncoding>() {
+            let weak = headers
+                .get::<ETag>()
+                .map(|&ETag(ref tag)| EntityTag::weak(tag.tag().to_owned()));
+            if let Some(tag) = weak {
+                headers.set(ETag(tag));
+            }
+        }
+        Response::new()
+            .with_status(status)
+            .with_headers(headers)
+            .with_body(body)
+    }))
+}
+
+#[test]
+fn gzip_roundtrip_test() {
+    let body = "monto ".repeat(1000).into_bytes();
+    let mut headers = Headers::new();
+    let compressed = gzip_if_large(body.clone(), &mut headers);
+    assert!(compressed.len() < body.len());
+    let decompressed = decompress(compressed, headers.get()).unwrap();
+    assert_eq!(decompressed, body);
+}
+
+#[test]
+fn decompress_limit_test() {
+    let body = vec![0; MAX_DECOMPRESSED_SIZE as usize + 1];
+    let mut headers = Headers::new();
+    let compressed = gzip_if_large(body, &mut headers);
+    assert!(decompress(compressed, headers.get()).is_err());
+}
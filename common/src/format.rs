@@ -0,0 +1,153 @@
+//! The formats messages can be serialized in.
+//!
+//! JSON is always supported, as required by the specification. CBOR is also
+//! supported, as it is much more compact for products such as highlighting
+//! tokens, which are mostly object keys when encoded as JSON. The format of a
+//! response is chosen with the `Accept` header, and the format of a request
+//! body is given by its `Content-Type` header.
+
+use hyper::header::{q, qitem, Accept, ContentType, Quality, QualityItem};
+use hyper::mime::{Mime, APPLICATION, JSON};
+use serde::Serialize;
+use serde::de::{DeserializeOwned, Error as SerdeErrorExt};
+use serde_cbor;
+use serde_json;
+use serde_json::error::Error as SerdeError;
+
+use headers::AcceptPost;
+
+/// A format messages can be serialized in.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// [JSON](https://tools.ietf.org/html/rfc8259), as used by the
+    /// specification.
+    Json,
+
+    /// [CBOR](https://tools.ietf.org/html/rfc7049).
+    Cbor,
+}
+
+impl Format {
+    /// Returns all the supported formats.
+    pub fn all() -> Vec<Format> {
+        vec![Format::Json, Format::Cbor]
+    }
+
+    /// Returns the MIME type of the format.
+    pub fn mime(&self) -> Mime {
+        match *self {
+            Format::Json => ::hyper::mime::APPLICATION_JSON,
+            Format::Cbor => "application/cbor".parse().unwrap(),
+        }
+    }
+
+    /// Returns the format with the given MIME type, if it is supported.
+    pub fn from_mime(mime: &Mime) -> Option<Format> {
+        if mime.type_() != APPLICATION {
+            None
+        } else if mime.subtype() == JSON {
+            Some(Format::Json)
+        } else if mime.subtype() == "cbor" {
+            Some(Format::Cbor)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the format of a body with the given `Content-Type`. If there is
+    /// no `Content-Type`, or it is not a supported format, JSON is assumed.
+    pub fn from_content_type(content_type: Option<&ContentType>) -> Format {
+        content_type
+            .and_then(|&ContentType(ref mime)| Format::from_mime(mime))
+            .unwrap_or(Format::Json)
+    }
+
+    /// Returns the most preferred supported format in an `Accept` header.
+    /// Defaults to JSON.
+    pub fn from_accept(accept: Option<&Accept>) -> Format {
+        let mut best: Option<(Format, Quality)> = None;
+        if let Some(&Accept(ref items)) = accept {
+            for item in items {
+                if let Some(format) = Format::from_mime(&item.item) {
+                    if best.map(|(_, q)| item.quality > q).unwrap_or(true) {
+                        best = Some((format, item.quality));
+                    }
+                }
+            }
+        }
+        best.map(|(format, _)| format).unwrap_or(Format::Json)
+    }
+
+    /// Returns the `Accept` header for a request preferring this format, but
+    /// also accepting JSON.
+    pub fn accept(&self) -> Accept {
+        let mut items = vec![qitem(self.mime())];
+        if *self != Format::Json {
+            items.push(QualityItem::new(Format::Json.mime(), q(0.5)));
+        }
+        Accept(items)
+    }
+
+    /// Returns this format if the server accepts it in request bodies, as
+    /// indicated by its `Accept-Post` header, and JSON otherwise.
+    pub fn if_accepted(self, accept_post: Option<&AcceptPost>) -> Format {
+        let accepted = accept_post
+            .map(|&AcceptPost(ref mimes)| mimes.iter().any(|m| Format::from_mime(m) == Some(self)))
+            .unwrap_or(false);
+        if accepted {
+            self
+        } else {
+            Format::Json
+        }
+    }
+
+    /// Serializes a value in this format.
+    pub fn serialize<T: Serialize>(&self, t: &T) -> Result<Vec<u8>, SerdeError> {
+        match *self {
+            Format::Json => serde_json::to_vec(t),
+            Format::Cbor => serde_cbor::to_vec(t).map_err(SerdeError::custom),
+        }
+    }
+
+    /// Deserializes a value from this format.
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, SerdeError> {
+        match *self {
+            Format::Json => serde_json::from_slice(bytes),
+            Format::Cbor => serde_cbor::from_slice(bytes).map_err(SerdeError::custom),
+        }
+    }
+}
+
+/// Returns an `Accept-Post` header listing all the supported formats.
+pub fn accept_post() -> AcceptPost {
+    AcceptPost(Format::all().iter().map(Format::mime).collect())
+}
+
+#[test]
+fn format_negotiation_test() {
+    assert_eq!(Format::from_accept(None), Format::Json);
+    assert_eq!(Format::from_accept(Some(&Format::Cbor.accept())), Format::Cbor);
+    assert_eq!(Format::from_accept(Some(&Format::Json.accept())), Format::Json);
+    assert_eq!(Format::Cbor.if_accepted(Some(&accept_post())), Format::Cbor);
+    assert_eq!(Format::Cbor.if_accepted(None), Format::Json);
+}
+
+#[test]
+fn format_roundtrip_test() {
+    use messages::{Language, Product, ProductName};
+
+    let product = Product {
+        name: ProductName::Highlighting,
+        language: Language::Text,
+        path: "/tmp/foo.txt".to_owned(),
+        value: serde_json::from_str(
+            r#"[{"start_byte": 0, "end_byte": 4, "color": {"type": "palette", "value": 1}}]"#,
+        ).unwrap(),
+    };
+    for format in Format::all() {
+        let bytes = format.serialize(&product).unwrap();
+        let product2: Product = format.deserialize(&bytes).unwrap();
+        assert_eq!(product2.value, product.value);
+    }
+}
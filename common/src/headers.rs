@@ -0,0 +1,19 @@
+//! HTTP headers used by the Broker, Clients, and Services beyond those
+//! required by the specification.
+
+use hyper::mime::Mime;
+
+header! {
+    /// The session a Client was given by the Broker during version
+    /// negotiation. Clients send this with every request, so the Broker can
+    /// tell which Client Protocol Extensions were negotiated with them.
+    (MontoSession, "Monto-Session") => [String]
+}
+
+header! {
+    /// The formats a server accepts in request bodies, as in the
+    /// [Linked Data Platform](https://www.w3.org/TR/ldp/#header-accept-post).
+    /// This is sent with every response, so that a peer knows whether it may
+    /// send a binary format.
+    (AcceptPost, "Accept-Post") => (Mime)*
+}
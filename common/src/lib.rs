@@ -2,30 +2,43 @@
 //! Protocols.
 
 extern crate either;
+extern crate flate2;
 extern crate futures;
+#[macro_use]
 extern crate hyper;
 #[macro_use]
 extern crate lazy_static;
 extern crate regex;
 extern crate semver;
 extern crate serde;
+extern crate serde_cbor;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate url;
 
+pub mod encoding;
+pub mod format;
+pub mod headers;
 pub mod messages;
+pub mod paths;
 pub mod products;
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
 use either::{Either, Left, Right};
 use futures::{Future, Stream};
 use futures::future::{err, ok};
-use hyper::{Body, Response, StatusCode};
+use hyper::{Body, Headers, Response, StatusCode};
 use hyper::Error as HyperError;
-use hyper::header::{ContentLength, ContentType};
+use hyper::header::{ContentEncoding, ContentLength, ContentType, ETag, EntityTag, IfNoneMatch};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use serde_json::error::Error as SerdeError;
 
+use format::Format;
+
 /// Creates an error response.
 pub fn error_response(
     status: StatusCode,
@@ -51,12 +64,38 @@ pub fn json_request<T: DeserializeOwned + 'static>(
     )
 }
 
+/// Deserializes an object from the request, in the format given by its
+/// `Content-Type` header, decompressing it according to its `Content-Encoding`
+/// header first.
+pub fn decode_request<T: DeserializeOwned + 'static>(
+    body: Body,
+    headers: &Headers,
+) -> Box<Future<Item = T, Error = Either<HyperError, SerdeError>>> {
+    let encoding = headers.get::<ContentEncoding>().cloned();
+    let format = Format::from_content_type(headers.get());
+    Box::new(body.concat2().map_err(Left).and_then(move |bs| {
+        encoding::decompress(bs.to_vec(), encoding.as_ref())
+            .map_err(SerdeError::io)
+            .and_then(|bs| format.deserialize(&bs))
+            .map_err(Right)
+    }))
+}
+
 /// Converts an object to JSON and serves it as a Response.
 pub fn json_response<T: Serialize>(
     t: T,
     status: StatusCode,
 ) -> Box<Future<Item = Response<Body>, Error = Either<HyperError, SerdeError>>> {
-    let res = match serde_json::to_string(&t) {
+    format_response(t, status, Format::Json)
+}
+
+/// Serializes an object in the given format and serves it as a Response.
+pub fn format_response<T: Serialize>(
+    t: T,
+    status: StatusCode,
+    format: Format,
+) -> Box<Future<Item = Response<Body>, Error = Either<HyperError, SerdeError>>> {
+    let res = match format.serialize(&t) {
         Ok(s) => s,
         Err(e) => return Box::new(err(Right(e))),
     };
@@ -64,7 +103,71 @@ pub fn json_response<T: Serialize>(
         Response::new()
             .with_status(status)
             .with_header(ContentLength(res.len() as u64))
-            .with_header(ContentType("application/json".parse().unwrap()))
+            .with_header(ContentType(format.mime()))
             .with_body(res),
     ))
 }
+
+/// Converts an object to JSON and serves it as a Response, with an `ETag`
+/// derived from the JSON. If the tag matches the given `If-None-Match` header,
+/// a `304 Not Modified` Response without a body is served instead.
+pub fn json_response_tagged<T: Serialize>(
+    t: T,
+    status: StatusCode,
+    if_none_match: Option<&IfNoneMatch>,
+) -> Box<Future<Item = Response<Body>, Error = Either<HyperError, SerdeError>>> {
+    format_response_tagged(t, status, Format::Json, if_none_match)
+}
+
+/// Serializes an object in the given format and serves it as a Response, with
+/// an `ETag` as `json_response_tagged` does.
+pub fn format_response_tagged<T: Serialize>(
+    t: T,
+    status: StatusCode,
+    format: Format,
+    if_none_match: Option<&IfNoneMatch>,
+) -> Box<Future<Item = Response<Body>, Error = Either<HyperError, SerdeError>>> {
+    let res = match format.serialize(&t) {
+        Ok(s) => s,
+        Err(e) => return Box::new(err(Right(e))),
+    };
+    let tag = {
+        let mut hasher = DefaultHasher::new();
+        res.hash(&mut hasher);
+        EntityTag::strong(format!("{:016x}", hasher.finish()))
+    };
+    let unchanged = match if_none_match {
+        Some(&IfNoneMatch::Any) => true,
+        Some(&IfNoneMatch::Items(ref tags)) => tags.iter().any(|t| t.weak_eq(&tag)),
+        None => false,
+    };
+    Box::new(ok(if unchanged {
+        Response::new()
+            .with_status(StatusCode::NotModified)
+            .with_header(ETag(tag))
+    } else {
+        Response::new()
+            .with_status(status)
+            .with_header(ContentLength(res.len() as u64))
+            .with_header(ContentType(format.mime()))
+            .with_header(ETag(tag))
+            .with_body(res)
+    }))
+}
+
+#[test]
+fn json_response_tagged_not_modified() {
+    let res = json_response_tagged("foo", StatusCode::Ok, None).wait().unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+    let tag = res.headers().get::<ETag>().unwrap().0.clone();
+
+    let inm = IfNoneMatch::Items(vec![tag]);
+    let res = json_response_tagged("foo", StatusCode::Ok, Some(&inm))
+        .wait()
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NotModified);
+    let res = json_response_tagged("bar", StatusCode::Ok, Some(&inm))
+        .wait()
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+}
@@ -2,14 +2,15 @@
 //! [Section 3.1](https://melt-umn.github.io/monto-v3-draft/draft03/#3-1-common-messages)
 //! of the specification.
 
-use std::cmp::Ordering;
+use std::cmp::{min, Ordering};
+use std::collections::BTreeSet;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::str::FromStr;
 
 use regex::Regex;
 use semver::Version as SemverVersion;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use serde::de::{Error as SerdeError, Visitor};
+use serde::de::{Error as SerdeError, Unexpected, Visitor};
 use serde_json::Value;
 
 /// A reverse-hostname-style dotted identifier, which must have at least two components.
@@ -185,9 +186,34 @@ pub struct NamespacedName {
     name: String,
 }
 
+impl NamespacedName {
+    /// Creates a new NamespacedName.
+    pub fn new(namespace: Identifier, name: String) -> NamespacedName {
+        NamespacedName { namespace, name }
+    }
+
+    /// Creates a NamespacedName in the namespace of Monto itself, used for
+    /// the extensions it defines.
+    pub fn monto(name: &str) -> NamespacedName {
+        let namespace = "edu.umn.cs.melt.monto".parse().unwrap();
+        NamespacedName::new(namespace, name.to_owned())
+    }
+}
+
 impl<'de> Deserialize<'de> for NamespacedName {
-    fn deserialize<D: Deserializer<'de>>(_d: D) -> Result<Self, D::Error> {
-        unimplemented!()
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct V;
+        impl<'de> Visitor<'de> for V {
+            type Value = NamespacedName;
+            fn expecting(&self, fmt: &mut Formatter) -> FmtResult {
+                write!(fmt, "a dotted identifier, followed by a slash and a name")
+            }
+            fn visit_str<E: SerdeError>(self, s: &str) -> Result<NamespacedName, E> {
+                s.parse()
+                    .map_err(|()| E::invalid_value(Unexpected::Str(s), &self))
+            }
+        }
+        d.deserialize_str(V)
     }
 }
 
@@ -197,6 +223,22 @@ impl Display for NamespacedName {
     }
 }
 
+impl FromStr for NamespacedName {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let idx = s.find('/').ok_or(())?;
+        let (namespace, name) = (&s[..idx], &s[idx + 1..]);
+        if name.is_empty() {
+            return Err(());
+        }
+        Ok(NamespacedName {
+            namespace: namespace.parse()?,
+            name: name.to_owned(),
+        })
+    }
+}
+
 impl Serialize for NamespacedName {
     fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
         self.to_string().serialize(s)
@@ -391,6 +433,52 @@ impl ProtocolVersion {
     pub fn compatible(&self, other: &ProtocolVersion) -> bool {
         self.major == other.major
     }
+
+    /// Returns the versions of the protocols implemented by this library.
+    pub fn supported() -> BTreeSet<ProtocolVersion> {
+        let mut versions = BTreeSet::new();
+        versions.insert(ProtocolVersion::new(3, 0, 0));
+        versions
+    }
+
+    /// Chooses the version to use given the versions supported by each side.
+    /// This is the highest version either side could use, i.e. the highest
+    /// version resulting from a pair of compatible versions.
+    ///
+    /// Returns `None` if no pair of versions is compatible.
+    pub fn negotiate(
+        ours: &BTreeSet<ProtocolVersion>,
+        theirs: &BTreeSet<ProtocolVersion>,
+    ) -> Option<ProtocolVersion> {
+        ours.iter()
+            .flat_map(|a| {
+                theirs
+                    .iter()
+                    .filter(move |b| a.compatible(b))
+                    .map(move |b| min(*a, *b))
+            })
+            .max()
+    }
+}
+
+#[test]
+fn protocol_version_negotiate_test() {
+    let set = |vs: &[(u64, u64, u64)]| -> BTreeSet<ProtocolVersion> {
+        vs.iter()
+            .map(|&(major, minor, patch)| ProtocolVersion::new(major, minor, patch))
+            .collect()
+    };
+
+    let ours = set(&[(3, 0, 0), (3, 1, 0), (4, 0, 0)]);
+    assert_eq!(
+        ProtocolVersion::negotiate(&ours, &set(&[(3, 2, 0)])),
+        Some(ProtocolVersion::new(3, 1, 0))
+    );
+    assert_eq!(
+        ProtocolVersion::negotiate(&ours, &set(&[(3, 0, 1), (4, 0, 2)])),
+        Some(ProtocolVersion::new(4, 0, 0))
+    );
+    assert_eq!(ProtocolVersion::negotiate(&ours, &set(&[(2, 0, 0)])), None);
 }
 
 impl Display for ProtocolVersion {
@@ -480,3 +568,11 @@ impl From<SoftwareVersion> for SemverVersion {
         }
     }
 }
+
+#[test]
+fn namespaced_name_parse_test() {
+    let nn: NamespacedName = "edu.umn.cs.melt/cancel".parse().unwrap();
+    assert_eq!(nn.to_string(), "edu.umn.cs.melt/cancel");
+    assert_eq!("edu.umn.cs.melt".parse::<NamespacedName>(), Err(()));
+    assert_eq!("edu.umn.cs.melt/".parse::<NamespacedName>(), Err(()));
+}
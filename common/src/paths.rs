@@ -0,0 +1,98 @@
+//! The paths of Products.
+//!
+//! A path is either a path on the filesystem or a URI. A `file:` URI refers
+//! to the same file as the equivalent plain path. URIs with other schemes,
+//! such as `untitled:` for documents that only exist in an editor, don't
+//! refer to files at all; their contents are only known from what Clients
+//! send.
+
+use std::env::current_dir;
+use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
+
+use url::Url;
+
+/// Returns the file a path refers to, or `None` if it is a URI with a scheme
+/// other than `file:`.
+pub fn file_path(path: &str) -> Option<PathBuf> {
+    match uri(path) {
+        Some(url) => if url.scheme() == "file" {
+            url.to_file_path().ok()
+        } else {
+            None
+        },
+        None => Some(PathBuf::from(path)),
+    }
+}
+
+/// Normalizes a path, so that paths referring to the same file are equal.
+/// `file:` URIs are replaced by the paths they refer to, and other paths are
+/// left alone.
+pub fn normalize(path: &str) -> String {
+    match uri(path) {
+        Some(ref url) if url.scheme() == "file" => match url.to_file_path() {
+            Ok(file) => file.display().to_string(),
+            Err(()) => path.to_owned(),
+        },
+        _ => path.to_owned(),
+    }
+}
+
+/// Normalizes a path, and makes it absolute if it refers to a file. Relative
+/// paths to files that exist are canonicalized; others are taken to be
+/// relative to the current directory.
+pub fn absolute(path: &str) -> IoResult<String> {
+    let file = match file_path(path) {
+        Some(file) => file,
+        None => return Ok(path.to_owned()),
+    };
+    let file = if file.is_absolute() {
+        file
+    } else if file.exists() {
+        file.canonicalize()?
+    } else {
+        current_dir()?.join(file)
+    };
+    Ok(file.display().to_string())
+}
+
+/// Parses a path as a URI, if it is one. Single-letter schemes aren't
+/// accepted, since those are Windows drive letters.
+fn uri(path: &str) -> Option<Url> {
+    match Url::parse(path) {
+        Ok(url) => if url.scheme().len() > 1 && !Path::new(path).is_absolute() {
+            Some(url)
+        } else {
+            None
+        },
+        Err(_) => None,
+    }
+}
+
+#[test]
+fn paths_test() {
+    let file = if cfg!(windows) {
+        "C:\\monto\\a.c"
+    } else {
+        "/monto/a.c"
+    };
+    let uri = Url::from_file_path(file).unwrap().into_string();
+
+    assert_eq!(file_path(file), Some(PathBuf::from(file)));
+    assert_eq!(file_path(&uri), Some(PathBuf::from(file)));
+    assert_eq!(file_path("untitled:Untitled-1"), None);
+
+    assert_eq!(normalize(&uri), file);
+    assert_eq!(normalize("untitled:Untitled-1"), "untitled:Untitled-1");
+
+    assert_eq!(absolute(&uri).unwrap(), file);
+    assert_eq!(absolute("untitled:Untitled-1").unwrap(), "untitled:Untitled-1");
+    assert_eq!(
+        absolute("monto-does-not-exist.c").unwrap(),
+        current_dir()
+            .unwrap()
+            .join("monto-does-not-exist.c")
+            .display()
+            .to_string()
+    );
+}
@@ -10,13 +10,14 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{Error as SerdeError, Unexpected, Visitor};
 use serde_json::{to_value, Value};
 
-use messages::{Language, Product, ProductName};
+use messages::{Identifier, Language, Product, ProductName};
 
 /// A listing of a directory.
 ///
 /// Defined in
 /// [Section 6.1](https://melt-umn.github.io/monto-v3-draft/draft03/#6-1-directory)
 /// of the specification.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Directory {
     /// The path at which the directory is present.
     pub path: String,
@@ -38,7 +39,7 @@ impl From<Directory> for Product {
 }
 
 /// A single entry in a directory.
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct DirectoryEntry {
     /// The basename of the file.
     pub name: String,
@@ -53,7 +54,7 @@ pub struct DirectoryEntry {
 
 /// The type of a directory entry.
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
-#[serde(rename_all = "snake_case", untagged)]
+#[serde(rename_all = "snake_case")]
 pub enum DirectoryEntryType {
     /// A regular file.
     File,
@@ -82,6 +83,14 @@ impl From<FileType> for DirectoryEntryType {
     }
 }
 
+#[test]
+fn directory_entry_type_serialize_test() {
+    assert_eq!(
+        to_value(DirectoryEntryType::Symlink).unwrap().to_string(),
+        r#""symlink""#
+    );
+}
+
 /// Syntactic or semantic errors detected in source code.
 ///
 /// Defined in
@@ -128,6 +137,11 @@ pub struct Error {
 
     /// The severity of the error.
     pub severity: ErrorSeverity,
+
+    /// The Services that detected the error. This is only set by Brokers that
+    /// merge the errors from several Services.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<Identifier>,
 }
 
 /// The severity of an error.
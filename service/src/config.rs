@@ -7,7 +7,7 @@ use std::path::{Path, PathBuf};
 
 use rand::random;
 
-use monto3_common::messages::{Identifier, SoftwareVersion};
+use monto3_common::messages::{Identifier, ProtocolVersion, SoftwareVersion};
 
 use messages::ServiceExtension;
 
@@ -25,6 +25,11 @@ error_chain! {
             description("A configuration file couldn't be parsed")
             display("The configuration file {} couldn't be parsed", path.display())
         }
+
+        /// The config did not list any protocol versions to use.
+        NoProtocols {
+            description("The config did not list any protocol versions")
+        }
     }
 
     foreign_links {
@@ -39,6 +44,7 @@ error_chain! {
 ///
 /// ```toml
 /// extensions = ["com.example.foo", "org.test.bar"]
+/// protocols = [{ major = 3, minor = 0, patch = 0 }]
 ///
 /// [net]
 /// addr = "[::]:28888"
@@ -53,6 +59,13 @@ pub struct Config {
     #[serde(default)]
     pub net: NetConfig,
 
+    /// The versions of the Service Protocol to accept. The highest version
+    /// shared with a Broker is used to communicate with it.
+    ///
+    /// Defaults to all the versions this library supports.
+    #[serde(default = "ProtocolVersion::supported")]
+    pub protocols: BTreeSet<ProtocolVersion>,
+
     /// Configuration on how the Service should report its version and implementation.
     #[serde(default)]
     pub version: VersionConfig,
@@ -145,6 +158,7 @@ impl Default for Config {
         Config {
             extensions: BTreeSet::new(),
             net: NetConfig::default(),
+            protocols: ProtocolVersion::supported(),
             version: VersionConfig::default(),
         }
     }
@@ -160,7 +174,9 @@ impl Default for Config {
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(default)]
 pub struct NetConfig {
-    /// The address to serve on. Defaults to `0.0.0.0:28888`.
+    /// The address to serve on. If the port is 0, an unused port is chosen.
+    ///
+    /// Defaults to `0.0.0.0:28888`.
     pub addr: SocketAddr,
 }
 
@@ -0,0 +1,87 @@
+//! Implementations of Service Protocol Extensions.
+
+use std::rc::Rc;
+
+use futures::Future;
+use futures::future::ok;
+use hyper::{Body, Error as HyperError, Headers, Method, Response, StatusCode, Uri};
+
+use monto3_common::{decode_request, error_response};
+use monto3_common::messages::{Product, ProductIdentifier};
+
+use messages::{BrokerRequest, ServiceExtension};
+
+/// A Future for the Response to a request handled by an extension.
+pub type ExtensionFuture = Box<Future<Item = Response, Error = HyperError>>;
+
+/// An implementation of a Service Protocol Extension.
+///
+/// The hooks are only called if the Broker negotiated the extension.
+pub trait ExtensionHandler {
+    /// Returns the extension being implemented.
+    fn extension(&self) -> ServiceExtension;
+
+    /// Handles a request the Service does not handle itself. If the extension
+    /// does not handle it either, the body should be returned.
+    fn handle(
+        &self,
+        _method: &Method,
+        _uri: &Uri,
+        _headers: &Headers,
+        body: Body,
+    ) -> Result<ExtensionFuture, Body> {
+        Err(body)
+    }
+
+    /// Modifies a request after it is received from the Broker.
+    fn request(&self, _req: &mut BrokerRequest) {}
+
+    /// Modifies a Product before it is sent to the Broker.
+    fn product(&self, _product: &mut Product) {}
+}
+
+/// An implementation of the `ServiceExtension::Cancel` extension, which calls
+/// a function with the products the Broker no longer needs.
+///
+/// Since products are provided synchronously, a product is usually done by the
+/// time it is cancelled; this is most useful for Services that do work in the
+/// background.
+pub struct Cancellation(Rc<Fn(ProductIdentifier)>);
+
+impl Cancellation {
+    /// Creates a handler that calls the given function with each product that
+    /// is cancelled.
+    pub fn new<F: Fn(ProductIdentifier) + 'static>(f: F) -> Cancellation {
+        Cancellation(Rc::new(f))
+    }
+}
+
+impl ExtensionHandler for Cancellation {
+    fn extension(&self) -> ServiceExtension {
+        ServiceExtension::Cancel
+    }
+
+    fn handle(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &Headers,
+        body: Body,
+    ) -> Result<ExtensionFuture, Body> {
+        if *method != Method::Post || uri.path() != "/monto/cancel" {
+            return Err(body);
+        }
+        let f = self.0.clone();
+        Ok(Box::new(decode_request(body, headers).then(move |r| match r {
+            Ok(pi) => {
+                info!("Cancelled {:?}", pi);
+                f(pi);
+                Box::new(ok(Response::new().with_status(StatusCode::NoContent)))
+            }
+            Err(e) => {
+                error!("{}", e);
+                error_response(StatusCode::BadRequest)
+            }
+        })))
+    }
+}
@@ -31,53 +31,116 @@ extern crate void;
 mod macros;
 
 pub mod config;
+pub mod extensions;
 pub mod helpers;
 pub mod messages;
 mod serve;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
 
+use hyper::{Body, Headers, Method, Uri};
 use serde_json::Value;
 use tokio_core::reactor::Handle;
 
-use monto3_common::messages::{Product, ProductDescriptor, ProtocolVersion};
+use monto3_common::messages::{Product, ProductDescriptor, ProductIdentifier, ProtocolVersion};
 
-use config::Config;
-use messages::{ServiceError, ServiceNegotiation, ServiceNotice};
+use config::{Config, ErrorKind as ConfigErrorKind, Result as ConfigResult};
+use extensions::{ExtensionFuture, ExtensionHandler};
+use messages::{BrokerRequest, ServiceBrokerNegotiation, ServiceError, ServiceErrors,
+               ServiceExtension, ServiceNegotiation, ServiceNotice, ServiceProduct};
 pub use serve::ServeFuture;
 
 /// A Service and the associated HTTP server.
 pub struct Service {
     config: Config,
+    enabled: BTreeSet<ServiceExtension>,
+    extensions: Vec<Rc<ExtensionHandler>>,
     funcs: BTreeMap<ProductDescriptor, Box<ServiceProvider>>,
     handle: Handle,
+    preferred: ProtocolVersion,
+    protocol: Option<ProtocolVersion>,
 }
 
 impl Service {
-    /// Creates a new Service.
-    pub fn new(config: Config, handle: Handle) -> Service {
+    /// Creates a new Service, or returns an error if the config doesn't list
+    /// any protocol versions.
+    pub fn new(config: Config, handle: Handle) -> ConfigResult<Service> {
+        let preferred = match config.protocols.iter().next_back() {
+            Some(&preferred) => preferred,
+            None => return Err(ConfigErrorKind::NoProtocols.into()),
+        };
         let funcs = BTreeMap::new();
-        Service {
+        Ok(Service {
             config,
+            enabled: BTreeSet::new(),
+            extensions: Vec::new(),
             funcs,
             handle,
-        }
+            preferred,
+            protocol: None,
+        })
     }
 
-    /// Creates a ServiceNegotiation.
+    /// Creates a ServiceNegotiation, offering the highest protocol version the
+    /// Service supports.
     pub fn negotiation(&self) -> ServiceNegotiation {
+        let mut extensions = self.config.extensions.clone();
+        extensions.extend(self.extensions.iter().map(|h| h.extension()));
         ServiceNegotiation {
-            extensions: self.config.extensions.clone(),
-            monto: ProtocolVersion {
-                major: 3,
-                minor: 0,
-                patch: 0,
-            },
+            extensions,
+            monto: self.preferred,
+            versions: self.config.protocols.clone(),
             products: self.funcs.keys().cloned().collect(),
             service: self.config.version.clone().into(),
         }
     }
 
+    /// Negotiates a protocol version and extensions with a Broker, returning
+    /// the ServiceNegotiation to respond with, and whether the Broker is
+    /// compatible.
+    pub fn negotiate(&mut self, sbn: &ServiceBrokerNegotiation) -> (ServiceNegotiation, bool) {
+        let mut sn = self.negotiation();
+        match ProtocolVersion::negotiate(&sn.supported(), &sbn.supported()) {
+            Some(protocol) => {
+                sn.monto = protocol;
+                self.protocol = Some(protocol);
+                self.enabled = sn.extensions
+                    .intersection(&sbn.extensions)
+                    .cloned()
+                    .collect();
+                (sn, true)
+            }
+            None => (sn, false),
+        }
+    }
+
+    /// Gives a request the Service does not handle itself to the handler for
+    /// each negotiated extension in turn, returning the Response of the first
+    /// one to handle it.
+    pub fn handle(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &Headers,
+        body: Body,
+    ) -> Option<ExtensionFuture> {
+        let mut body = body;
+        for handler in self.handlers() {
+            match handler.handle(method, uri, headers, body) {
+                Ok(future) => return Some(future),
+                Err(b) => body = b,
+            }
+        }
+        None
+    }
+
+    /// Returns the Service Protocol version negotiated with the Broker, if
+    /// negotiation has taken place.
+    pub fn protocol(&self) -> Option<ProtocolVersion> {
+        self.protocol
+    }
+
     /// Adds a ServiceProvider to the service.
     ///
     /// Replaces any ServiceProvider that provides the same Product.
@@ -85,6 +148,68 @@ impl Service {
         let descriptor = provider.descriptor();
         self.funcs.insert(descriptor, Box::new(provider));
     }
+
+    /// Adds a handler for a Service Protocol Extension to the service.
+    ///
+    /// Replaces any handler for the same extension.
+    pub fn add_extension<H: ExtensionHandler + 'static>(&mut self, handler: H) {
+        let extension = handler.extension();
+        self.extensions.retain(|h| h.extension() != extension);
+        self.extensions.push(Rc::new(handler));
+    }
+
+    /// Handles a BrokerRequest by running the appropriate ServiceProvider.
+    ///
+    /// This is what the Service does when it receives a request over HTTP, but
+    /// it may also be called directly to use the Service in-process.
+    pub fn provide(&mut self, br: BrokerRequest) -> Result<ServiceProduct, ProvideError> {
+        let mut br = br;
+        let handlers = self.handlers();
+        for handler in &handlers {
+            handler.request(&mut br);
+        }
+        let BrokerRequest { request, products } = br;
+        let descriptor: ProductDescriptor = request.clone().into();
+        let provider = match self.funcs.get_mut(&descriptor) {
+            Some(provider) => provider,
+            None => return Err(ProvideError::NotExposed(request)),
+        };
+        let (r, notices) = provider.service(&request.path, products);
+        match r {
+            Ok(value) => {
+                let mut product = Product {
+                    name: descriptor.name,
+                    language: descriptor.language,
+                    path: request.path,
+                    value,
+                };
+                for handler in &handlers {
+                    handler.product(&mut product);
+                }
+                Ok(ServiceProduct { product, notices })
+            }
+            Err(errors) => Err(ProvideError::ServiceErrors(ServiceErrors { errors, notices })),
+        }
+    }
+
+    /// Returns the handlers for the extensions negotiated with the Broker.
+    fn handlers(&self) -> Vec<Rc<ExtensionHandler>> {
+        self.extensions
+            .iter()
+            .filter(|h| self.enabled.contains(&h.extension()))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The reasons a Service can fail to provide a product.
+#[derive(Clone, Debug)]
+pub enum ProvideError {
+    /// No ServiceProvider provides the requested product.
+    NotExposed(ProductIdentifier),
+
+    /// The ServiceProvider failed to produce the product.
+    ServiceErrors(ServiceErrors),
 }
 
 /// A function for a service.
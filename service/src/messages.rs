@@ -4,6 +4,8 @@
 
 use std::collections::BTreeSet;
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
 use monto3_common::messages::{NamespacedName, Product, ProductDescriptor, ProductIdentifier,
                               ProtocolVersion, SoftwareVersion};
 
@@ -17,6 +19,12 @@ pub struct ServiceBrokerNegotiation {
     /// The version of the Service Protocol the Broker supports.
     pub monto: ProtocolVersion,
 
+    /// Other versions of the Service Protocol the Broker supports, in addition to
+    /// `monto`. This is not part of the specification, so it may be absent, in
+    /// which case only `monto` is supported.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub versions: BTreeSet<ProtocolVersion>,
+
     /// The version information of the Broker.
     pub broker: SoftwareVersion,
 
@@ -25,6 +33,15 @@ pub struct ServiceBrokerNegotiation {
     pub extensions: BTreeSet<ServiceExtension>,
 }
 
+impl ServiceBrokerNegotiation {
+    /// Returns all the versions of the Service Protocol the Broker supports.
+    pub fn supported(&self) -> BTreeSet<ProtocolVersion> {
+        let mut versions = self.versions.clone();
+        versions.insert(self.monto);
+        versions
+    }
+}
+
 /// The Message that a Service sends to a Broker during version negotiation.
 ///
 /// Defined in
@@ -32,9 +49,16 @@ pub struct ServiceBrokerNegotiation {
 /// of the specification.
 #[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct ServiceNegotiation {
-    /// The version of the Service Protocol the Service supports.
+    /// The version of the Service Protocol the Service chose to use, or the highest
+    /// version it supports if none are compatible.
     pub monto: ProtocolVersion,
 
+    /// Other versions of the Service Protocol the Service supports, in addition to
+    /// `monto`. This is not part of the specification, so it may be absent, in
+    /// which case only `monto` is supported.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub versions: BTreeSet<ProtocolVersion>,
+
     /// The version information of the Service.
     pub service: SoftwareVersion,
 
@@ -46,14 +70,64 @@ pub struct ServiceNegotiation {
     pub products: BTreeSet<ProductDescriptor>,
 }
 
-/// An extension to the Service Protocol.
-#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
-#[serde(rename_all = "snake_case", untagged)]
+impl ServiceNegotiation {
+    /// Returns all the versions of the Service Protocol the Service supports.
+    pub fn supported(&self) -> BTreeSet<ProtocolVersion> {
+        let mut versions = self.versions.clone();
+        versions.insert(self.monto);
+        versions
+    }
+}
+
+/// An extension to the Service Protocol. Extensions are negotiated by
+/// their names.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum ServiceExtension {
-    /// An unknown and unsupported extension.
+    /// The extension for the Broker to tell the Service when it no longer
+    /// needs a product it requested, because a newer version of the source has
+    /// been sent to it. The Broker sends a `POST` to `/monto/cancel` with the
+    /// `ProductIdentifier` of the product as the body.
+    ///
+    /// It is implemented for Services by `extensions::Cancellation`.
+    Cancel,
+
+    /// An extension known only by its name. Extensions implemented by an
+    /// `ExtensionHandler` are also negotiated by name, so they use this too.
     Unknown(NamespacedName),
 }
 
+impl ServiceExtension {
+    /// Returns the name the extension is negotiated under.
+    pub fn name(&self) -> NamespacedName {
+        match *self {
+            ServiceExtension::Cancel => NamespacedName::monto("cancel"),
+            ServiceExtension::Unknown(ref name) => name.clone(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ServiceExtension {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        NamespacedName::deserialize(d).map(ServiceExtension::from)
+    }
+}
+
+impl From<NamespacedName> for ServiceExtension {
+    fn from(name: NamespacedName) -> ServiceExtension {
+        if name == NamespacedName::monto("cancel") {
+            ServiceExtension::Cancel
+        } else {
+            ServiceExtension::Unknown(name)
+        }
+    }
+}
+
+impl Serialize for ServiceExtension {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.name().serialize(s)
+    }
+}
+
 /// The Message that a Service sends to a Broker during version negotiation.
 ///
 /// Defined in
@@ -122,3 +196,15 @@ pub enum ServiceNotice {
     /// A notice that a dependency was unused when producing a Product.
     UnusedDependency(ProductIdentifier),
 }
+
+#[test]
+fn service_extension_serde_test() {
+    use serde_json::{from_str, to_string};
+
+    let cancel: ServiceExtension = from_str(r#""edu.umn.cs.melt.monto/cancel""#).unwrap();
+    assert_eq!(cancel, ServiceExtension::Cancel);
+    assert_eq!(to_string(&cancel).unwrap(), r#""edu.umn.cs.melt.monto/cancel""#);
+
+    let other: ServiceExtension = from_str(r#""edu.umn.cs.melt/other""#).unwrap();
+    assert_eq!(other, ServiceExtension::Unknown("edu.umn.cs.melt/other".parse().unwrap()));
+}
@@ -1,35 +1,46 @@
 use std::cell::RefCell;
 use std::io::Error as IoError;
+use std::net::SocketAddr;
 use std::rc::Rc;
 
 use either::{Either, Left, Right};
 use futures::{empty, Async, Empty, Future, Poll, Stream};
 use futures::future::err;
 use hyper::{Body, Error as HyperError, Method, Request, Response, StatusCode};
+use hyper::header::AcceptEncoding;
 use hyper::server::{Http, Service as HyperService};
 use log::LogLevel;
 use tokio_core::net::{Incoming, TcpListener};
 use tokio_core::reactor::Handle;
 use void::Void;
 
-use monto3_common::{error_response, json_request, json_response};
-use monto3_common::messages::{Product, ProductDescriptor};
+use monto3_common::{decode_request, error_response, format_response, json_response};
+use monto3_common::encoding::compress_response;
+use monto3_common::format::{accept_post, Format};
 
-use Service;
-use messages::{BrokerRequest, ServiceBrokerNegotiation, ServiceErrors, ServiceProduct};
+use {ProvideError, Service};
+use messages::{BrokerRequest, ServiceBrokerNegotiation};
 
 impl Service {
     /// Serves until the given future resolves.
+    ///
+    /// If the configured port is 0, an unused port is chosen by the operating
+    /// system. The address actually being served on can be retrieved with
+    /// `ServeFuture::local_addr`.
     pub fn serve_until<F: Future>(self, stop: F) -> ServeFuture<F> {
         let listener = TcpListener::bind(&self.config.net.addr, &self.handle)
-            .expect("TODO proper error handling")
-            .incoming();
+            .expect("TODO proper error handling");
+        let addr = listener
+            .local_addr()
+            .expect("TODO proper error handling");
+        info!("Serving on {}", addr);
         let handle = self.handle.clone();
         let service = Rc::new(RefCell::new(self));
         ServeFuture {
+            addr,
             handle,
             http: Http::new(),
-            listener,
+            listener: listener.incoming(),
             service,
             stop,
         }
@@ -50,21 +61,20 @@ impl HyperService for Broker {
     type Future = Box<Future<Item = Response<Body>, Error = HyperError>>;
 
     fn call(&self, req: Request) -> Self::Future {
-        let (method, uri, _, _, body) = req.deconstruct();
+        let (method, uri, _, headers, body) = req.deconstruct();
+        let accept = headers.get::<AcceptEncoding>().cloned();
+        let format = Format::from_accept(headers.get());
         let f: Box<Future<Item = _, Error = HyperError>> = match (method.clone(), uri.path()) {
             (Method::Post, "/monto/version") => {
                 let service = self.0.clone();
                 Box::new(
-                    json_request(body)
+                    decode_request(body, &headers)
                         .and_then(move |sbn: ServiceBrokerNegotiation| {
                             debug!("Got ServiceBrokerNegotiation {:?}", sbn);
-                            let sn = service.borrow().negotiation();
-                            let status = if sbn.monto.compatible(&sn.monto) {
-                                StatusCode::Ok
-                            } else {
-                                StatusCode::BadRequest
-                            };
-                            json_response(sn, status)
+                            match service.borrow_mut().negotiate(&sbn) {
+                                (sn, true) => json_response(sn, StatusCode::Ok),
+                                (sn, false) => json_response(sn, StatusCode::BadRequest),
+                            }
                         })
                         .or_else(|e| {
                             // Log the error.
@@ -82,38 +92,19 @@ impl HyperService for Broker {
             (Method::Post, "/monto/service") => {
                 let service = self.0.clone();
                 Box::new(
-                    json_request(body)
+                    decode_request(body, &headers)
                         .and_then(move |br: BrokerRequest| {
                             debug!("Got BrokerRequest {:?}", br);
-                            let BrokerRequest { request, products } = br;
-                            let descriptor: ProductDescriptor = request.clone().into();
-                            let mut service = service.borrow_mut();
-                            if let Some(provider) = service.funcs.get_mut(&descriptor) {
-                                let (r, notices) = provider.service(&request.path, products);
-                                match r {
-                                    Ok(val) => json_response(
-                                        ServiceProduct {
-                                            product: Product {
-                                                name: descriptor.name,
-                                                language: descriptor.language,
-                                                path: request.path,
-                                                value: val,
-                                            },
-                                            notices: notices,
-                                        },
-                                        StatusCode::Ok,
-                                    ),
-                                    Err(errors) => {
-                                        error!("{:?}", errors);
-                                        json_response(
-                                            ServiceErrors { errors, notices },
-                                            StatusCode::InternalServerError,
-                                        )
-                                    }
+                            match service.borrow_mut().provide(br) {
+                                Ok(sp) => format_response(sp, StatusCode::Ok, format),
+                                Err(ProvideError::NotExposed(pi)) => {
+                                    warn!("Couldn't find a provider for {:?}", pi);
+                                    format_response(pi, StatusCode::BadRequest, format)
+                                }
+                                Err(ProvideError::ServiceErrors(ses)) => {
+                                    error!("{:?}", ses.errors);
+                                    format_response(ses, StatusCode::InternalServerError, format)
                                 }
-                            } else {
-                                warn!("Couldn't find a provider for {:?}", descriptor);
-                                json_response(request, StatusCode::BadRequest)
                             }
                         })
                         .or_else(|e| {
@@ -129,8 +120,13 @@ impl HyperService for Broker {
                         }),
                 )
             }
-            _ => error_response(StatusCode::NotFound),
+            _ => self.0
+                .borrow()
+                .handle(&method, &uri, &headers, body)
+                .unwrap_or_else(|| error_response(StatusCode::NotFound)),
         };
+        let f = f.map(|r| r.with_header(accept_post()))
+            .and_then(move |r| compress_response(r, accept.as_ref()));
         Box::new(f.map(move |r: Response| {
             let status = r.status();
             let level = if status.is_server_error() || status.is_strange_status() {
@@ -148,6 +144,7 @@ impl HyperService for Broker {
 
 /// A Future for a Service serving to Brokers.
 pub struct ServeFuture<F: Future> {
+    addr: SocketAddr,
     handle: Handle,
     http: Http,
     listener: Incoming,
@@ -155,6 +152,13 @@ pub struct ServeFuture<F: Future> {
     stop: F,
 }
 
+impl<F: Future> ServeFuture<F> {
+    /// Returns the address being served on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
 impl<F: Future> Future for ServeFuture<F> {
     type Item = F::Item;
     type Error = Either<F::Error, IoError>;
@@ -183,3 +187,16 @@ impl<F: Future> Future for ServeFuture<F> {
         }
     }
 }
+
+#[test]
+fn ephemeral_port_test() {
+    use tokio_core::reactor::Core;
+
+    use config::Config;
+
+    let core = Core::new().unwrap();
+    let mut config = Config::default();
+    config.net.addr = "127.0.0.1:0".parse().unwrap();
+    let serve = Service::new(config, core.handle()).unwrap().serve_forever();
+    assert_ne!(serve.local_addr().port(), 0);
+}
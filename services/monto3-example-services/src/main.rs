@@ -31,7 +31,10 @@ fn main() {
     pretty_logger::init_to_defaults().unwrap();
     let mut c = Core::new().unwrap();
     let config = Config::load("example_services");
-    let mut s = Service::new(config, c.handle());
+    let mut s = match Service::new(config, c.handle()) {
+        Ok(s) => s,
+        Err(e) => return error!("{}", e),
+    };
 
     s.add_provider(CharCount);
     s.add_provider(LineCount);
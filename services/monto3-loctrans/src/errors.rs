@@ -56,6 +56,7 @@ impl MeltError {
             severity: self.severity,
             start_byte: s,
             end_byte: e,
+            services: Vec::new(),
         })
     }
 }
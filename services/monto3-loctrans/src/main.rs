@@ -1,5 +1,8 @@
 //! A Monto service that translates the products given by the ableC service to
 //! those given by the Monto specification.
+//!
+//! The Broker can do the same translation itself with `[[translate]]` rules in
+//! its configuration, without running this service.
 
 extern crate either;
 #[macro_use]
@@ -37,7 +40,10 @@ fn main() {
     pretty_logger::init_to_defaults().unwrap();
     let mut c = Core::new().unwrap();
     let config = Config::load("monto-loctrans");
-    let mut s = Service::new(config, c.handle());
+    let mut s = match Service::new(config, c.handle()) {
+        Ok(s) => s,
+        Err(e) => return error!("{}", e),
+    };
 
     s.add_provider(Errors);
     s.add_provider(Highlighting);
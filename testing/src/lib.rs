@@ -0,0 +1,537 @@
+//! Support for testing a Broker, Services, and a Client together, in a single
+//! process.
+//!
+//! Everything runs on one event loop, and serves on ephemeral ports on
+//! localhost, so tests can run in parallel.
+//!
+//! ```no_run
+//! # extern crate monto3_common;
+//! # extern crate monto3_service;
+//! # extern crate monto3_testing;
+//! # use monto3_common::messages::{Language, ProductName};
+//! # use monto3_service::Service;
+//! # use monto3_testing::Harness;
+//! # fn main() {
+//! let mut harness = Harness::new();
+//! let mut service = Service::new(harness.service_config(), harness.handle()).unwrap();
+//! // service.add_provider(...);
+//! harness.add_service(service);
+//!
+//! let mut running = harness.start().unwrap();
+//! running.send_source("/test/foo.txt", Language::Text, "foo").unwrap();
+//! let product = running.request(
+//!     "com.example.service",
+//!     ProductName::Highlighting,
+//!     Language::Text,
+//!     "/test/foo.txt",
+//! ).unwrap();
+//! # }
+//! ```
+
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate log;
+extern crate monto3_broker;
+extern crate monto3_client;
+extern crate monto3_common;
+#[cfg_attr(test, macro_use)]
+extern crate monto3_service;
+extern crate serde_json;
+extern crate tokio_core;
+
+pub mod mock;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use serde_json::Value;
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+use monto3_broker::{Broker, NewBrokerError, NewBrokerErrorKind};
+use monto3_broker::config::{Config as BrokerConfig, ServiceConfig};
+use monto3_broker::resolve::Cache;
+use monto3_client::{Client, Config as ClientConfig, NegotiationError, NegotiationErrorKind,
+                    RequestError, SendError};
+use monto3_common::messages::{Identifier, Language, Product, ProductIdentifier, ProductName};
+use monto3_common::products::Source;
+use monto3_service::Service;
+use monto3_service::config::Config as ServiceConfigFile;
+
+use mock::MockService;
+
+/// A Broker and Services that have not been started yet.
+pub struct Harness {
+    broker: BrokerConfig,
+    client: ClientConfig,
+    core: Core,
+    in_process: Vec<Service>,
+}
+
+impl Harness {
+    /// Creates a new Harness, with its own event loop.
+    ///
+    /// Panics if the event loop can't be created.
+    pub fn new() -> Harness {
+        let core = Core::new().expect("Couldn't create event loop");
+        let mut broker = BrokerConfig::default();
+        broker.net.addr = localhost();
+        Harness {
+            broker,
+            client: ClientConfig::default(),
+            core,
+            in_process: Vec::new(),
+        }
+    }
+
+    /// Returns a handle to the event loop, which Services must be created
+    /// with.
+    pub fn handle(&self) -> Handle {
+        self.core.handle()
+    }
+
+    /// Returns the configuration the Broker will be started with, which may be
+    /// modified. The address it serves on is replaced when it is started.
+    pub fn broker_config(&mut self) -> &mut BrokerConfig {
+        &mut self.broker
+    }
+
+    /// Returns the configuration the Client will be started with, which may be
+    /// modified. The host and port it connects to are replaced when it is
+    /// started.
+    pub fn client_config(&mut self) -> &mut ClientConfig {
+        &mut self.client
+    }
+
+    /// Returns a configuration for a Service, serving on an ephemeral port.
+    pub fn service_config(&self) -> ServiceConfigFile {
+        let mut config = ServiceConfigFile::default();
+        config.net.addr = localhost();
+        config
+    }
+
+    /// Starts serving a Service over HTTP, and configures the Broker to
+    /// connect to it. Returns the address the Service is serving on.
+    pub fn add_service(&mut self, service: Service) -> SocketAddr {
+        let serve = service.serve_forever();
+        let addr = serve.local_addr();
+        self.core.handle().spawn(
+            serve
+                .map(|_| ())
+                .map_err(|e| error!("Service stopped: {:?}", e)),
+        );
+        self.connect_to(addr);
+        addr
+    }
+
+    /// Starts serving a MockService, and configures the Broker to connect to
+    /// it. Returns the address the MockService is serving on.
+    pub fn add_mock_service(&mut self, mock: MockService) -> SocketAddr {
+        let addr = mock.serve(&localhost());
+        self.connect_to(addr);
+        addr
+    }
+
+    /// Configures the Broker to connect to a Service at the given address.
+    pub fn connect_to(&mut self, addr: SocketAddr) {
+        self.broker.service.push(ServiceConfig {
+            addr: addr.to_string(),
+            base: "/monto".to_owned(),
+            command: Vec::new(),
+            dir: None,
+            env: BTreeMap::new(),
+            scheme: "http".to_owned(),
+        });
+    }
+
+    /// Adds a Service to run in the Broker's process.
+    pub fn add_in_process_service(&mut self, service: Service) {
+        self.in_process.push(service);
+    }
+
+    /// Starts the Broker, and connects a Client to it.
+    pub fn start(self) -> Result<Running, HarnessError> {
+        let Harness {
+            broker,
+            mut client,
+            mut core,
+            in_process,
+        } = self;
+
+        let mut broker = core.run(Broker::new(broker, core.handle()))?;
+        for service in in_process {
+            broker.add_service(service);
+        }
+        let cache = broker.cache();
+        let serve = broker.serve_forever();
+        let broker_addr = serve.local_addr();
+        core.handle().spawn(serve.then(|_| Ok(())));
+
+        client.host = broker_addr.ip().to_string();
+        client.port = broker_addr.port();
+        let client = core.run(Client::new(client, core.handle()))?;
+        Ok(Running {
+            broker_addr,
+            cache,
+            client,
+            core,
+        })
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Harness {
+        Harness::new()
+    }
+}
+
+/// A running Broker and Services, and a Client connected to them.
+pub struct Running {
+    broker_addr: SocketAddr,
+    cache: Rc<RefCell<Cache>>,
+    client: Client,
+    core: Core,
+}
+
+impl Running {
+    /// Returns the address the Broker is serving on.
+    pub fn broker_addr(&self) -> SocketAddr {
+        self.broker_addr
+    }
+
+    /// Returns the Client connected to the Broker.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// Runs a future to completion on the event loop.
+    pub fn run<F: Future>(&mut self, future: F) -> Result<F::Item, F::Error> {
+        self.core.run(future)
+    }
+
+    /// Runs the event loop until the Broker has cached the given product from
+    /// the Service with the given identifier, such as one being computed in
+    /// the background.
+    ///
+    /// Panics if the identifier is invalid, or if the product isn't cached
+    /// within ten seconds.
+    pub fn wait_until_cached(&mut self, service: &str, pi: &ProductIdentifier) {
+        let service: Identifier = service.parse().expect("Invalid service identifier");
+        let start = Instant::now();
+        while self.cache.borrow().get(&service, pi.clone()).is_none() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "{:?} from {} was never cached",
+                pi,
+                service
+            );
+            let tick = Timeout::new(Duration::from_millis(10), &self.core.handle())
+                .expect("Couldn't create timeout");
+            self.core.run(tick).expect("Couldn't wait for timeout");
+        }
+    }
+
+    /// Runs the event loop until the Broker has checked every path it is
+    /// watching for changes, evicting the products at the ones that changed,
+    /// as it does when it may have missed a change.
+    pub fn revalidate(&mut self) {
+        let revalidate = Cache::revalidate_all(&self.cache);
+        self.core.run(revalidate).expect("Couldn't revalidate the cache");
+    }
+
+    /// Sends a source to the Broker. The path does not need to exist, and
+    /// may be a URI, such as `untitled:Untitled-1`.
+    pub fn send_source(
+        &mut self,
+        path: &str,
+        language: Language,
+        contents: &str,
+    ) -> Result<(), SendError> {
+        let future = self.client.send_product(Source {
+            contents: contents.to_owned(),
+            language,
+            path: path.to_owned(),
+        });
+        self.core.run(future)
+    }
+
+    /// Requests a product from the Service with the given identifier.
+    ///
+    /// Panics if the identifier is invalid.
+    pub fn request(
+        &mut self,
+        service: &str,
+        name: ProductName,
+        language: Language,
+        path: &str,
+    ) -> Result<Product, RequestError> {
+        let service: Identifier = service.parse().expect("Invalid service identifier");
+        let pi = ProductIdentifier {
+            name,
+            language,
+            path: path.to_owned(),
+        };
+        let future = self.client.request(&service, &pi);
+        self.core.run(future)
+    }
+
+    /// Requests a product, and asserts that it has the given value.
+    ///
+    /// Panics if the product couldn't be retrieved, or has a different value.
+    pub fn assert_product(
+        &mut self,
+        service: &str,
+        name: ProductName,
+        language: Language,
+        path: &str,
+        expected: Value,
+    ) {
+        match self.request(service, name.clone(), language.clone(), path) {
+            Ok(product) => assert_eq!(
+                product.value,
+                expected,
+                "Unexpected value for {} {} {} from {}",
+                name,
+                language,
+                path,
+                service
+            ),
+            Err(e) => panic!(
+                "Couldn't get {} {} {} from {}: {}",
+                name,
+                language,
+                path,
+                service,
+                e
+            ),
+        }
+    }
+}
+
+/// Returns an address on localhost with an ephemeral port.
+fn localhost() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+error_chain! {
+    types {
+        HarnessError, HarnessErrorKind, HarnessResultExt;
+    }
+    links {
+        Broker(NewBrokerError, NewBrokerErrorKind)
+            #[doc = "The Broker couldn't be started."];
+        Client(NegotiationError, NegotiationErrorKind)
+            #[doc = "The Client couldn't connect to the Broker."];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, File};
+    use std::io::Write;
+
+    use futures::Stream;
+    use serde_json::Value;
+
+    use monto3_client::RequestErrorKind;
+    use monto3_client::messages::{QueryResponse, WorkspaceQuery};
+    use monto3_common::messages::{Language, ProductName};
+    use monto3_service::Service;
+    use monto3_service::helpers::simple_fn;
+
+    use super::Harness;
+
+    simple_service_provider! {
+        name = Length;
+        product = "edu.umn.cs.melt.monto_testing.length";
+        language = "text";
+        (p, ps) => {
+            simple_fn(p, ps, Language::Text, |src| -> Result<_, &str> {
+                Ok(src.len().into())
+            })
+        }
+    }
+
+    /// Adds a Service providing `Length` to the harness, either over HTTP or
+    /// in the Broker's process.
+    fn length_service(harness: &mut Harness, in_process: bool) {
+        let mut config = harness.service_config();
+        config.version.id = "edu.umn.cs.melt.monto_testing".parse().unwrap();
+        let mut service = Service::new(config, harness.handle()).unwrap();
+        service.add_provider(Length);
+        if in_process {
+            harness.add_in_process_service(service);
+        } else {
+            harness.add_service(service);
+        }
+    }
+
+    #[test]
+    fn http_service_test() {
+        let mut harness = Harness::new();
+        length_service(&mut harness, false);
+
+        let path = temp_dir().join("monto-testing-foo.txt").display().to_string();
+        let mut running = harness.start().unwrap();
+        running
+            .send_source(&path, Language::Text, "hello")
+            .unwrap();
+        running.assert_product(
+            "edu.umn.cs.melt.monto_testing",
+            "edu.umn.cs.melt.monto_testing.length".parse::<ProductName>().unwrap(),
+            Language::Text,
+            &path,
+            5.into(),
+        );
+    }
+
+    #[test]
+    fn in_process_service_test() {
+        let mut harness = Harness::new();
+        length_service(&mut harness, true);
+
+        let path = temp_dir().join("monto-testing-bar.txt").display().to_string();
+        let mut running = harness.start().unwrap();
+        running
+            .send_source(&path, Language::Text, "hi")
+            .unwrap();
+        running.assert_product(
+            "edu.umn.cs.melt.monto_testing",
+            "edu.umn.cs.melt.monto_testing.length".parse::<ProductName>().unwrap(),
+            Language::Text,
+            &path,
+            2.into(),
+        );
+    }
+
+    #[test]
+    fn rate_limit_test() {
+        let mut harness = Harness::new();
+        harness.broker_config().limits.rate = Some(1);
+        length_service(&mut harness, false);
+
+        let path = temp_dir().join("monto-testing-baz.txt").display().to_string();
+        let mut running = harness.start().unwrap();
+        running
+            .send_source(&path, Language::Text, "hello")
+            .unwrap();
+        let result = running.request(
+            "edu.umn.cs.melt.monto_testing",
+            "edu.umn.cs.melt.monto_testing.length".parse::<ProductName>().unwrap(),
+            Language::Text,
+            &path,
+        );
+        match result {
+            Err(e) => match *e.kind() {
+                RequestErrorKind::RateLimited(Some(_)) => {}
+                ref kind => panic!("Expected to be rate limited, got {}", kind),
+            },
+            Ok(product) => panic!("Expected to be rate limited, got {:?}", product),
+        }
+    }
+
+    #[test]
+    fn query_test() {
+        let root = temp_dir().join("monto-testing-query");
+        let _ = remove_dir_all(&root);
+        for &(path, contents) in &[
+            ("a.txt", "a"),
+            ("b.c", "bb"),
+            ("sub/c.txt", "ccc"),
+            ("sub/d.json", "dddd"),
+        ] {
+            let path = root.join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path)
+                .unwrap()
+                .write_all(contents.as_bytes())
+                .unwrap();
+        }
+
+        let mut harness = Harness::new();
+        length_service(&mut harness, true);
+        let mut running = harness.start().unwrap();
+
+        let mut query = WorkspaceQuery {
+            service: "edu.umn.cs.melt.monto_testing".parse().unwrap(),
+            product: "edu.umn.cs.melt.monto_testing.length".parse().unwrap(),
+            root: root.canonicalize().unwrap().display().to_string(),
+            language: Some(Language::Text),
+            glob: None,
+        };
+        let stream = running.client().query(&query);
+        let lengths = running
+            .run(stream.collect())
+            .unwrap()
+            .into_iter()
+            .map(|response| match response {
+                QueryResponse::Product(product) => product.value,
+                QueryResponse::Error { product, error } => {
+                    panic!("Couldn't get {:?}: {}", product, error)
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lengths, vec![Value::from(1), Value::from(3)]);
+
+        // With a glob, files are requested in the given language whatever
+        // their extension.
+        query.glob = Some("sub/*".to_owned());
+        let stream = running.client().query(&query);
+        assert_eq!(running.run(stream.collect()).unwrap().len(), 2);
+
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn uri_path_test() {
+        let mut harness = Harness::new();
+        length_service(&mut harness, true);
+        let mut running = harness.start().unwrap();
+        let length = || {
+            "edu.umn.cs.melt.monto_testing.length"
+                .parse::<ProductName>()
+                .unwrap()
+        };
+
+        // Documents that aren't files are only known from what was sent.
+        running
+            .send_source("untitled:Untitled-1", Language::Text, "hello")
+            .unwrap();
+        running.assert_product(
+            "edu.umn.cs.melt.monto_testing",
+            length(),
+            Language::Text,
+            "untitled:Untitled-1",
+            5.into(),
+        );
+        assert!(
+            running
+                .request(
+                    "edu.umn.cs.melt.monto_testing",
+                    length(),
+                    Language::Text,
+                    "untitled:Untitled-2",
+                )
+                .is_err()
+        );
+
+        // A file: URI refers to the same source as the plain path.
+        let path = temp_dir().join("monto-testing-uri.txt");
+        let uri = format!("file://{}", path.display());
+        running.send_source(&uri, Language::Text, "hi").unwrap();
+        running.assert_product(
+            "edu.umn.cs.melt.monto_testing",
+            length(),
+            Language::Text,
+            &path.display().to_string(),
+            2.into(),
+        );
+    }
+}
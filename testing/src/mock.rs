@@ -0,0 +1,468 @@
+//! A Service whose responses are scripted, and which can inject faults into
+//! the Service Protocol, for testing how the Broker handles misbehaving
+//! Services.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use futures::future::{empty, err, ok};
+use futures::sync::oneshot::{Receiver, Sender};
+use hyper::{Body, Error as HyperError, Method, Request, Response, StatusCode};
+use hyper::header::{ContentLength, ContentType};
+use hyper::server::{Http, Service as HyperService};
+use serde_json;
+use serde_json::Value;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Handle, Timeout};
+
+use monto3_common::decode_request;
+use monto3_common::messages::{Product, ProductDescriptor, ProductIdentifier};
+use monto3_service::{ProvideError, Service, ServiceProvider};
+use monto3_service::config::{Config, Result as ConfigResult};
+use monto3_service::extensions::ExtensionHandler;
+use monto3_service::messages::{BrokerRequest, ServiceBrokerNegotiation, ServiceError,
+                               ServiceNotice};
+
+/// A fault to inject into the response to a request for a product.
+#[derive(Debug)]
+pub enum Fault {
+    /// Respond as usual, but only after the given delay.
+    Delay(Duration),
+
+    /// Never respond.
+    Hang,
+
+    /// Respond with a body that isn't valid JSON.
+    MalformedJson,
+
+    /// Send to the first channel when the request is received, then respond
+    /// as usual once the second channel is sent to or dropped. This lets a
+    /// test act while a request is in progress.
+    Pause(Sender<()>, Receiver<()>),
+
+    /// Respond with the given status, and an empty body.
+    Status(StatusCode),
+}
+
+/// The faults to inject into a MockService's responses, in order. Faults may
+/// be added after the MockService has started.
+#[derive(Clone, Debug, Default)]
+pub struct Faults(Rc<RefCell<VecDeque<Fault>>>);
+
+impl Faults {
+    /// Injects a fault into the next response that doesn't already have one.
+    pub fn push(&self, fault: Fault) {
+        self.0.borrow_mut().push_back(fault);
+    }
+
+    fn next(&self) -> Option<Fault> {
+        self.0.borrow_mut().pop_front()
+    }
+}
+
+/// A Service that provides scripted products, and can inject faults into its
+/// responses.
+///
+/// This is served with its own HTTP server rather than
+/// `monto3_service::Service::serve_until`, so that it can misbehave in ways a
+/// real Service wouldn't; requests are still handled by
+/// `monto3_service::Service`'s `negotiate`, `provide`, and `handle`.
+pub struct MockService {
+    faults: Faults,
+    handle: Handle,
+    requests: Rc<RefCell<Vec<BrokerRequest>>>,
+    service: Service,
+}
+
+impl MockService {
+    /// Creates a new MockService, with no providers.
+    pub fn new(config: Config, handle: Handle) -> ConfigResult<MockService> {
+        Ok(MockService {
+            faults: Faults::default(),
+            handle: handle.clone(),
+            requests: Rc::new(RefCell::new(Vec::new())),
+            service: Service::new(config, handle)?,
+        })
+    }
+
+    /// Adds a ServiceProvider to the service.
+    pub fn add_provider<P: ServiceProvider + 'static>(&mut self, provider: P) {
+        self.service.add_provider(provider);
+    }
+
+    /// Adds a handler for a Service Protocol Extension to the service.
+    pub fn add_extension<H: ExtensionHandler + 'static>(&mut self, handler: H) {
+        self.service.add_extension(handler);
+    }
+
+    /// Returns the faults to inject into the responses.
+    pub fn faults(&self) -> Faults {
+        self.faults.clone()
+    }
+
+    /// Returns a handle to the requests for products the Service has received.
+    pub fn requests(&self) -> Rc<RefCell<Vec<BrokerRequest>>> {
+        self.requests.clone()
+    }
+
+    /// Starts serving on the configured address, returning the address
+    /// actually being served on.
+    ///
+    /// Panics if the address can't be bound.
+    pub fn serve(self, addr: &SocketAddr) -> SocketAddr {
+        let listener = TcpListener::bind(addr, &self.handle).expect("Couldn't bind MockService");
+        let addr = listener.local_addr().expect("Couldn't bind MockService");
+        let handle = self.handle.clone();
+        let mock = Rc::new(MockHttp {
+            faults: self.faults,
+            handle: self.handle.clone(),
+            requests: self.requests,
+            service: RefCell::new(self.service),
+        });
+        let http = Http::new();
+        let serve = listener.incoming().for_each(move |(stream, remote)| {
+            http.bind_connection(&handle, stream, remote, MockConnection(mock.clone()));
+            Ok(())
+        });
+        self.handle
+            .spawn(serve.map_err(|e| error!("MockService stopped: {}", e)));
+        addr
+    }
+}
+
+struct MockHttp {
+    faults: Faults,
+    handle: Handle,
+    requests: Rc<RefCell<Vec<BrokerRequest>>>,
+    service: RefCell<Service>,
+}
+
+impl MockHttp {
+    fn respond(&self, br: BrokerRequest) -> Response {
+        self.requests.borrow_mut().push(br.clone());
+        let result = self.service.borrow_mut().provide(br);
+        let (status, body) = match result {
+            Ok(sp) => (StatusCode::Ok, serde_json::to_vec(&sp)),
+            Err(ProvideError::NotExposed(pi)) => (
+                StatusCode::BadRequest,
+                serde_json::to_vec(&ProductDescriptor::from(pi)),
+            ),
+            Err(ProvideError::ServiceErrors(ses)) => {
+                (StatusCode::InternalServerError, serde_json::to_vec(&ses))
+            }
+        };
+        json(status, body.expect("Couldn't serialize response"))
+    }
+}
+
+struct MockConnection(Rc<MockHttp>);
+
+impl HyperService for MockConnection {
+    type Request = Request;
+    type Response = Response;
+    type Error = HyperError;
+    type Future = Box<Future<Item = Response, Error = HyperError>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let mock = self.0.clone();
+        let (method, uri, _, headers, body) = req.deconstruct();
+        match (method.clone(), uri.path()) {
+            (Method::Post, "/monto/version") => Box::new(decode_request(body, &headers).then(
+                move |r: Result<ServiceBrokerNegotiation, _>| match r {
+                    Ok(sbn) => {
+                        let (sn, compatible) = mock.service.borrow_mut().negotiate(&sbn);
+                        let status = if compatible {
+                            StatusCode::Ok
+                        } else {
+                            StatusCode::BadRequest
+                        };
+                        let body = serde_json::to_vec(&sn).expect("Couldn't serialize negotiation");
+                        Ok(json(status, body))
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        Ok(Response::new().with_status(StatusCode::BadRequest))
+                    }
+                },
+            )),
+            (Method::Post, "/monto/service") => Box::new(body.concat2().and_then(
+                move |body| -> Box<Future<Item = Response, Error = HyperError>> {
+                    let br: BrokerRequest = match serde_json::from_slice(&body) {
+                        Ok(br) => br,
+                        Err(e) => {
+                            error!("{}", e);
+                            return Box::new(ok(Response::new().with_status(StatusCode::BadRequest)));
+                        }
+                    };
+                    match mock.faults.next() {
+                        None => Box::new(ok(mock.respond(br))),
+                        Some(Fault::Delay(delay)) => match Timeout::new(delay, &mock.handle) {
+                            Ok(timeout) => Box::new(
+                                timeout
+                                    .map_err(HyperError::from)
+                                    .map(move |()| mock.respond(br)),
+                            ),
+                            Err(e) => Box::new(err(e.into())),
+                        },
+                        Some(Fault::Hang) => Box::new(empty()),
+                        Some(Fault::MalformedJson) => {
+                            Box::new(ok(json(StatusCode::Ok, b"{\"product\":".to_vec())))
+                        }
+                        Some(Fault::Pause(received, resume)) => {
+                            let _ = received.send(());
+                            Box::new(resume.then(move |_| Ok(mock.respond(br))))
+                        }
+                        Some(Fault::Status(status)) => {
+                            Box::new(ok(Response::new().with_status(status)))
+                        }
+                    }
+                },
+            )),
+            _ => mock.service
+                .borrow()
+                .handle(&method, &uri, &headers, body)
+                .unwrap_or_else(|| Box::new(ok(Response::new().with_status(StatusCode::NotFound)))),
+        }
+    }
+}
+
+/// Creates a JSON response.
+fn json(status: StatusCode, body: Vec<u8>) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(Body::from(body))
+}
+
+/// A ServiceProvider whose products are scripted.
+///
+/// For each path, a product can either be given outright, or be computed from
+/// other products. Requests for other paths fail with an error.
+pub struct MockProvider {
+    descriptor: ProductDescriptor,
+    scripts: BTreeMap<String, Script>,
+}
+
+/// Computes a product from the products it depends on.
+type DependsFn = Box<Fn(&[Product]) -> Value>;
+
+enum Script {
+    Errors(Vec<ServiceError>),
+    Product(Value),
+    Depends(Vec<ProductIdentifier>, DependsFn),
+}
+
+impl MockProvider {
+    /// Creates a MockProvider for the given product, with no scripts.
+    pub fn new(descriptor: ProductDescriptor) -> MockProvider {
+        MockProvider {
+            descriptor,
+            scripts: BTreeMap::new(),
+        }
+    }
+
+    /// Provides the given value for the product at the given path.
+    pub fn product(mut self, path: &str, value: Value) -> MockProvider {
+        self.scripts.insert(path.to_owned(), Script::Product(value));
+        self
+    }
+
+    /// Fails with the given errors when the product at the given path is
+    /// requested.
+    pub fn errors(mut self, path: &str, errors: Vec<ServiceError>) -> MockProvider {
+        self.scripts.insert(path.to_owned(), Script::Errors(errors));
+        self
+    }
+
+    /// Computes the product at the given path from the given dependencies.
+    /// Until all the dependencies are sent by the Broker, requests fail with
+    /// an `UnmetDependency` error for each missing one.
+    pub fn depends<F>(mut self, path: &str, deps: Vec<ProductIdentifier>, f: F) -> MockProvider
+    where
+        F: Fn(&[Product]) -> Value + 'static,
+    {
+        self.scripts
+            .insert(path.to_owned(), Script::Depends(deps, Box::new(f)));
+        self
+    }
+}
+
+impl ServiceProvider for MockProvider {
+    fn descriptor(&self) -> ProductDescriptor {
+        self.descriptor.clone()
+    }
+
+    fn service(
+        &mut self,
+        path: &str,
+        products: Vec<Product>,
+    ) -> (Result<Value, Vec<ServiceError>>, Vec<ServiceNotice>) {
+        match self.scripts.get(path) {
+            Some(&Script::Product(ref value)) => (Ok(value.clone()), Vec::new()),
+            Some(&Script::Errors(ref errors)) => (Err(errors.clone()), Vec::new()),
+            Some(&Script::Depends(ref deps, ref f)) => {
+                let missing = deps.iter()
+                    .filter(|&dep| !products.iter().any(|p| &ProductIdentifier::from(p.clone()) == dep))
+                    .cloned()
+                    .map(ServiceError::UnmetDependency)
+                    .collect::<Vec<_>>();
+                if missing.is_empty() {
+                    (Ok(f(&products)), Vec::new())
+                } else {
+                    (Err(missing), Vec::new())
+                }
+            }
+            None => (
+                Err(vec![ServiceError::Other(format!("No script for {}", path))]),
+                Vec::new(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::time::Duration;
+
+    use futures::Future;
+    use futures::future::Either;
+    use futures::sync::oneshot::channel as oneshot;
+    use hyper::StatusCode;
+    use serde_json::Value;
+
+    use monto3_client::{RequestError, RequestErrorKind};
+    use monto3_client::messages::BrokerGetError;
+    use monto3_common::messages::{Language, Product, ProductDescriptor, ProductIdentifier,
+                                  ProductName};
+    use monto3_service::messages::ServiceError;
+
+    use {Harness, Running};
+    use super::{Fault, Faults, MockProvider, MockService};
+
+    const SERVICE: &str = "edu.umn.cs.melt.monto_testing.mock";
+
+    fn name(name: &str) -> ProductName {
+        format!("edu.umn.cs.melt.monto_testing.{}", name)
+            .parse()
+            .unwrap()
+    }
+
+    fn pi(product: &str, path: &str) -> ProductIdentifier {
+        ProductIdentifier {
+            name: name(product),
+            language: Language::Text,
+            path: path.to_owned(),
+        }
+    }
+
+    fn descriptor(product: &str) -> ProductDescriptor {
+        ProductDescriptor {
+            name: name(product),
+            language: Language::Text,
+        }
+    }
+
+    fn value(products: &[Product]) -> u64 {
+        products[0].value.as_u64().unwrap()
+    }
+
+    /// Starts a MockService that provides `a`, which depends on `b`, which
+    /// depends on the source, which is sent by the Client. The source's path
+    /// is named after `test`, so tests don't share files.
+    fn start(test: &str, timeout: u64) -> (Running, Faults, String) {
+        let path = temp_dir()
+            .join(format!("monto-testing-mock-{}.txt", test))
+            .display()
+            .to_string();
+        let source = ProductIdentifier {
+            name: ProductName::Source,
+            language: Language::Text,
+            path: path.clone(),
+        };
+
+        let mut harness = Harness::new();
+        harness.broker_config().broker.service_timeout = timeout;
+        let mut config = harness.service_config();
+        config.version.id = SERVICE.parse().unwrap();
+        let mut mock = MockService::new(config, harness.handle()).unwrap();
+        mock.add_provider(MockProvider::new(descriptor("a")).depends(
+            &path,
+            vec![pi("b", &path)],
+            |ps| (value(ps) + 1).into(),
+        ));
+        mock.add_provider(MockProvider::new(descriptor("b")).depends(
+            &path,
+            vec![source],
+            |ps| ps[0].value.as_str().unwrap().len().into(),
+        ));
+        mock.add_provider(MockProvider::new(descriptor("c")).errors(
+            &path,
+            vec![ServiceError::Other("scripted".to_owned())],
+        ));
+        let faults = mock.faults();
+        harness.add_mock_service(mock);
+
+        let mut running = harness.start().unwrap();
+        running.send_source(&path, Language::Text, "hello").unwrap();
+        (running, faults, path)
+    }
+
+    fn service_error(result: Result<Product, RequestError>) -> String {
+        match result {
+            Err(RequestError(
+                RequestErrorKind::Broker(BrokerGetError::ServiceError { error, .. }),
+                _,
+            )) => error,
+            r => panic!("Expected a ServiceError, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn unmet_dependency_chain_test() {
+        let (mut running, _, path) = start("unmet", 30_000);
+        running.assert_product(SERVICE, name("a"), Language::Text, &path, Value::from(6));
+        let error = service_error(running.request(SERVICE, name("c"), Language::Text, &path));
+        assert_eq!(error, "scripted");
+    }
+
+    #[test]
+    fn fault_test() {
+        let (mut running, faults, path) = start("fault", 100);
+
+        faults.push(Fault::Status(StatusCode::ImATeapot));
+        let error = service_error(running.request(SERVICE, name("b"), Language::Text, &path));
+        assert!(error.contains("418"), "{}", error);
+
+        faults.push(Fault::MalformedJson);
+        service_error(running.request(SERVICE, name("b"), Language::Text, &path));
+
+        faults.push(Fault::Hang);
+        let error = service_error(running.request(SERVICE, name("b"), Language::Text, &path));
+        assert!(error.contains("in time"), "{}", error);
+
+        // Products are cached once they succeed, so only one request for b
+        // reaches the Service from here on.
+        let (received_send, received) = oneshot();
+        let (resume, resumed) = oneshot();
+        faults.push(Fault::Pause(received_send, resumed));
+        let request = running
+            .client()
+            .request(&SERVICE.parse().unwrap(), &pi("b", &path));
+        let request = match running.run(request.select2(received)) {
+            Ok(Either::B((_, request))) => request,
+            _ => panic!("The request for b never reached the Service"),
+        };
+        resume.send(()).unwrap();
+        let product = running.run(request).unwrap();
+        assert_eq!(product.value, Value::from(5));
+
+        faults.push(Fault::Delay(Duration::from_millis(10)));
+        running.assert_product(SERVICE, name("a"), Language::Text, &path, Value::from(6));
+    }
+}
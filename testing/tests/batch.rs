@@ -0,0 +1,54 @@
+extern crate monto3_client;
+extern crate monto3_common;
+extern crate monto3_testing;
+extern crate serde_json;
+
+use std::env::temp_dir;
+
+use serde_json::Value;
+
+use monto3_client::messages::BrokerGetError;
+use monto3_common::messages::{Identifier, Language, ProductDescriptor, ProductIdentifier,
+                              ProductName};
+use monto3_testing::Harness;
+use monto3_testing::mock::{MockProvider, MockService};
+
+const SERVICE: &str = "edu.umn.cs.melt.monto_testing.batch";
+
+#[test]
+fn batch_test() {
+    let path = temp_dir()
+        .join("monto-testing-batch.txt")
+        .display()
+        .to_string();
+    let name: ProductName = "edu.umn.cs.melt.monto_testing.answer".parse().unwrap();
+    let pi = ProductIdentifier {
+        name: name.clone(),
+        language: Language::Text,
+        path: path.clone(),
+    };
+
+    let mut harness = Harness::new();
+    let mut config = harness.service_config();
+    config.version.id = SERVICE.parse().unwrap();
+    let mut mock = MockService::new(config, harness.handle()).unwrap();
+    let descriptor = ProductDescriptor {
+        name,
+        language: Language::Text,
+    };
+    mock.add_provider(MockProvider::new(descriptor).product(&path, Value::from(42)));
+    harness.add_mock_service(mock);
+
+    // An unknown Service fails only its own item, not the whole batch.
+    let unknown: Identifier = "edu.umn.cs.melt.monto_testing.unknown".parse().unwrap();
+    let mut running = harness.start().unwrap();
+    let requests = vec![(SERVICE.parse().unwrap(), pi.clone()), (unknown, pi)];
+    let batch = running.client().request_many(&requests);
+    let mut responses = running.run(batch).unwrap().into_iter();
+    match responses.next() {
+        Some(Ok(product)) => assert_eq!(product.value, Value::from(42)),
+        r => panic!("Expected a product, got {:?}", r),
+    }
+    assert_eq!(responses.next(), Some(Err(BrokerGetError::NoSuchService)));
+    assert_eq!(responses.next(), None);
+}
@@ -0,0 +1,123 @@
+extern crate futures;
+extern crate monto3_client;
+extern crate monto3_common;
+extern crate monto3_service;
+extern crate monto3_testing;
+extern crate serde_json;
+
+use std::env::temp_dir;
+use std::fs::{self, File};
+use std::io::Write;
+
+use futures::{Future, Stream};
+use futures::future::Either;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+use futures::sync::oneshot::channel as oneshot;
+use serde_json::Value;
+
+use monto3_client::{RequestError, RequestErrorKind};
+use monto3_client::messages::BrokerGetError;
+use monto3_common::messages::{Language, Product, ProductDescriptor, ProductIdentifier,
+                              ProductName};
+use monto3_service::extensions::Cancellation;
+use monto3_testing::{Harness, Running};
+use monto3_testing::mock::{Fault, Faults, MockProvider, MockService};
+
+const SERVICE: &str = "edu.umn.cs.melt.monto_testing.cancel";
+
+fn length() -> ProductName {
+    "edu.umn.cs.melt.monto_testing.length".parse().unwrap()
+}
+
+fn length_pi(path: &str) -> ProductIdentifier {
+    ProductIdentifier {
+        name: length(),
+        language: Language::Text,
+        path: path.to_owned(),
+    }
+}
+
+/// Starts a MockService that provides the length of the source at `path`,
+/// and records the products it is told were cancelled.
+fn start(path: &str) -> (Running, Faults, UnboundedReceiver<ProductIdentifier>) {
+    let source = ProductIdentifier {
+        name: ProductName::Source,
+        language: Language::Text,
+        path: path.to_owned(),
+    };
+
+    let mut harness = Harness::new();
+    let mut config = harness.service_config();
+    config.version.id = SERVICE.parse().unwrap();
+    let mut mock = MockService::new(config, harness.handle()).unwrap();
+    let descriptor = ProductDescriptor {
+        name: length(),
+        language: Language::Text,
+    };
+    mock.add_provider(MockProvider::new(descriptor).depends(
+        path,
+        vec![source],
+        |ps: &[Product]| ps[0].value.as_str().unwrap().len().into(),
+    ));
+    let (cancel, cancelled) = unbounded();
+    mock.add_extension(Cancellation::new(move |pi| {
+        let _ = cancel.unbounded_send(pi);
+    }));
+    let faults = mock.faults();
+    harness.add_mock_service(mock);
+
+    (harness.start().unwrap(), faults, cancelled)
+}
+
+#[test]
+fn cancel_test() {
+    let path = temp_dir()
+        .join("monto-testing-cancel.txt")
+        .display()
+        .to_string();
+    let (mut running, faults, cancelled) = start(&path);
+    running.send_source(&path, Language::Text, "hello").unwrap();
+
+    // The source changes while the length is being computed.
+    let (received_send, received) = oneshot();
+    let (resume, resumed) = oneshot();
+    faults.push(Fault::Pause(received_send, resumed));
+    let request = running
+        .client()
+        .request(&SERVICE.parse().unwrap(), &length_pi(&path));
+    let request = match running.run(request.select2(received)) {
+        Ok(Either::B((_, request))) => request,
+        _ => panic!("The request for the length never reached the Service"),
+    };
+    running.send_source(&path, Language::Text, "bye").unwrap();
+    match running.run(request) {
+        Err(RequestError(RequestErrorKind::Broker(BrokerGetError::SourceChanged), _)) => {}
+        r => panic!("Expected a SourceChanged, got {:?}", r),
+    }
+    drop(resume);
+
+    match running.run(cancelled.into_future()) {
+        Ok((Some(cancelled), _)) => assert_eq!(cancelled, length_pi(&path)),
+        _ => panic!("The request for the length was never cancelled"),
+    }
+    running.assert_product(SERVICE, length(), Language::Text, &path, Value::from(3));
+}
+
+#[test]
+fn reread_source_test() {
+    let path = temp_dir().join("monto-testing-cancel-reread.txt");
+    File::create(&path).unwrap().write_all(b"hello").unwrap();
+    let path_str = path.display().to_string();
+    let (mut running, _, _) = start(&path_str);
+
+    // The source is read from disk, since it was never sent.
+    running.assert_product(SERVICE, length(), Language::Text, &path_str, Value::from(5));
+
+    // Once the file changes and is evicted, reading it again isn't mistaken
+    // for the source changing during the computation.
+    File::create(&path).unwrap().write_all(b"hi").unwrap();
+    running.revalidate();
+    running.assert_product(SERVICE, length(), Language::Text, &path_str, Value::from(2));
+
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,65 @@
+extern crate monto3_common;
+extern crate monto3_testing;
+extern crate serde_json;
+
+use std::env::temp_dir;
+
+use serde_json::from_str;
+
+use monto3_common::messages::{Language, ProductDescriptor, ProductName};
+use monto3_testing::Harness;
+use monto3_testing::mock::{MockProvider, MockService};
+
+const SERVICE: &str = "edu.umn.cs.melt.monto_testing.merged_errors";
+const SERVICE2: &str = "edu.umn.cs.melt.monto_testing.merged_errors2";
+
+#[test]
+fn merged_errors_test() {
+    let path = temp_dir()
+        .join("monto-testing-merged-errors.txt")
+        .display()
+        .to_string();
+    let errors = ProductDescriptor {
+        name: ProductName::Errors,
+        language: Language::Text,
+    };
+
+    let mut harness = Harness::new();
+    let broker = harness.broker_config().version.id.to_string();
+    for &(id, value) in &[
+        (
+            SERVICE,
+            r#"[{ "message": "b", "start_byte": 2, "end_byte": 3, "severity": "error" },
+                { "message": "a", "start_byte": 0, "end_byte": 1, "severity": "warning" }]"#,
+        ),
+        (
+            SERVICE2,
+            r#"[{ "message": "b", "start_byte": 2, "end_byte": 3, "severity": "error" },
+                { "message": "c", "start_byte": 1, "end_byte": 2, "severity": "info" }]"#,
+        ),
+    ] {
+        let mut config = harness.service_config();
+        config.version.id = id.parse().unwrap();
+        let mut mock = MockService::new(config, harness.handle()).unwrap();
+        mock.add_provider(
+            MockProvider::new(errors.clone()).product(&path, from_str(value).unwrap()),
+        );
+        harness.add_mock_service(mock);
+    }
+
+    let mut running = harness.start().unwrap();
+    running.send_source(&path, Language::Text, "abc").unwrap();
+    running.assert_product(
+        &broker,
+        ProductName::Errors,
+        Language::Text,
+        &path,
+        from_str(&format!(
+            r#"[{{ "message": "a", "start_byte": 0, "end_byte": 1, "severity": "warning", "services": ["{0}"] }},
+                {{ "message": "c", "start_byte": 1, "end_byte": 2, "severity": "info", "services": ["{1}"] }},
+                {{ "message": "b", "start_byte": 2, "end_byte": 3, "severity": "error", "services": ["{0}", "{1}"] }}]"#,
+            SERVICE,
+            SERVICE2
+        )).unwrap(),
+    );
+}
@@ -0,0 +1,88 @@
+extern crate monto3_broker;
+extern crate monto3_common;
+extern crate monto3_testing;
+extern crate serde_json;
+
+use std::env::temp_dir;
+
+use serde_json::Value;
+
+use monto3_broker::config::PrecomputeConfig;
+use monto3_common::messages::{Language, Product, ProductDescriptor, ProductIdentifier,
+                              ProductName};
+use monto3_testing::Harness;
+use monto3_testing::mock::{MockProvider, MockService};
+
+const SERVICE: &str = "edu.umn.cs.melt.monto_testing.precompute";
+
+fn name(name: &str) -> ProductName {
+    format!("edu.umn.cs.melt.monto_testing.{}", name)
+        .parse()
+        .unwrap()
+}
+
+fn pi(product: &str, path: &str) -> ProductIdentifier {
+    ProductIdentifier {
+        name: name(product),
+        language: Language::Text,
+        path: path.to_owned(),
+    }
+}
+
+fn descriptor(product: &str) -> ProductDescriptor {
+    ProductDescriptor {
+        name: name(product),
+        language: Language::Text,
+    }
+}
+
+#[test]
+fn precompute_test() {
+    let path = temp_dir()
+        .join("monto-testing-precompute.txt")
+        .display()
+        .to_string();
+    let source = ProductIdentifier {
+        name: ProductName::Source,
+        language: Language::Text,
+        path: path.clone(),
+    };
+
+    let mut harness = Harness::new();
+    harness.broker_config().precompute = vec![
+        PrecomputeConfig {
+            glob: Some("**/monto-testing-precompute.txt".to_owned()),
+            language: Some(Language::Text),
+            products: vec![name("a")],
+        },
+    ];
+    let mut config = harness.service_config();
+    config.version.id = SERVICE.parse().unwrap();
+    let mut mock = MockService::new(config, harness.handle()).unwrap();
+    mock.add_provider(MockProvider::new(descriptor("a")).depends(
+        &path,
+        vec![pi("b", &path)],
+        |ps: &[Product]| (ps[0].value.as_u64().unwrap() + 1).into(),
+    ));
+    mock.add_provider(MockProvider::new(descriptor("b")).depends(
+        &path,
+        vec![source],
+        |ps: &[Product]| ps[0].value.as_str().unwrap().len().into(),
+    ));
+    let requests = mock.requests();
+    harness.add_mock_service(mock);
+
+    let mut running = harness.start().unwrap();
+    running.send_source(&path, Language::Text, "hello").unwrap();
+    running.wait_until_cached(SERVICE, &pi("a", &path));
+
+    let precomputed = requests.borrow().len();
+    assert!(precomputed > 0);
+    running.assert_product(SERVICE, name("a"), Language::Text, &path, Value::from(6));
+    running.assert_product(SERVICE, name("b"), Language::Text, &path, Value::from(5));
+    assert_eq!(requests.borrow().len(), precomputed);
+
+    // Sending the source again evicts the products computed from it.
+    running.send_source(&path, Language::Text, "hi").unwrap();
+    running.assert_product(SERVICE, name("b"), Language::Text, &path, Value::from(2));
+}
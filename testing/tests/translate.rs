@@ -0,0 +1,84 @@
+extern crate monto3_broker;
+extern crate monto3_common;
+extern crate monto3_testing;
+extern crate serde_json;
+
+use std::env::temp_dir;
+
+use serde_json::from_str;
+
+use monto3_broker::config::{PositionConfig, TranslateConfig};
+use monto3_common::messages::{Language, ProductDescriptor, ProductName};
+use monto3_testing::Harness;
+use monto3_testing::mock::{MockProvider, MockService};
+
+const SERVICE: &str = "edu.umn.cs.melt.monto_testing.translate";
+
+#[test]
+fn translate_test() {
+    let path = temp_dir()
+        .join("monto-testing-translate.txt")
+        .display()
+        .to_string();
+    let errors: ProductName = "edu.umn.cs.melt.monto_testing.errors".parse().unwrap();
+
+    let mut harness = Harness::new();
+    let broker = harness.broker_config().version.id.to_string();
+    harness.broker_config().translate = vec![
+        TranslateConfig {
+            from: errors.clone(),
+            to: ProductName::Errors,
+            language: Language::Text,
+            fields: vec![
+                ("message".to_owned(), "msg".to_owned()),
+                ("severity".to_owned(), "severity".to_owned()),
+            ].into_iter()
+                .collect(),
+            positions: vec![
+                (
+                    "start_byte".to_owned(),
+                    PositionConfig {
+                        line: "line".to_owned(),
+                        column: "col".to_owned(),
+                    },
+                ),
+                (
+                    "end_byte".to_owned(),
+                    PositionConfig {
+                        line: "line".to_owned(),
+                        column: "end".to_owned(),
+                    },
+                ),
+            ].into_iter()
+                .collect(),
+            one_based: false,
+        },
+    ];
+    let mut config = harness.service_config();
+    config.version.id = SERVICE.parse().unwrap();
+    let mut mock = MockService::new(config, harness.handle()).unwrap();
+    let descriptor = ProductDescriptor {
+        name: errors,
+        language: Language::Text,
+    };
+    mock.add_provider(MockProvider::new(descriptor).product(
+        &path,
+        from_str(r#"[{ "msg": "oops", "severity": "error", "line": 1, "col": 1, "end": 2 }]"#)
+            .unwrap(),
+    ));
+    harness.add_mock_service(mock);
+
+    let mut running = harness.start().unwrap();
+    running.send_source(&path, Language::Text, "a\nbc").unwrap();
+    running.assert_product(
+        &broker,
+        ProductName::Errors,
+        Language::Text,
+        &path,
+        from_str(&format!(
+            r#"[{{ "message": "oops", "start_byte": 3, "end_byte": 4, "severity": "error",
+                   "services": ["{}"] }}]"#,
+            broker
+        )).unwrap(),
+    );
+}